@@ -0,0 +1,290 @@
+#![cfg_attr(all(feature = "mesalock_sgx", not(target_env = "sgx")), no_std)]
+#![cfg_attr(
+    all(target_env = "sgx", target_vendor = "mesalock"),
+    feature(rustc_private)
+)]
+
+#[cfg(all(feature = "mesalock_sgx", not(target_env = "sgx")))]
+#[macro_use]
+extern crate sgx_tstd as std;
+
+use chain_core::common::H256;
+use chain_core::state::account::StakedState;
+use chain_core::state::tendermint::BlockHeight;
+use chain_core::tx::data::{txid_hash, TxId};
+use chain_core::tx::fee::{Fee, LinearFee};
+use chain_core::tx::witness::tree::RawPubkey;
+use chain_tx_validation::Error;
+use enclave_protocol::{EnclaveResponse, VerifyTxRequest};
+use parity_scale_codec::{Decode, Encode};
+use std::prelude::v1::{Box, Vec};
+
+/// The public parameters wallets need for obfuscating transaction payloads:
+/// `key_from` goes to `TxObfuscated.key_from`, and the payload key is derived
+/// via ECDH between an ephemeral key and `public_key`
+/// (the compressed ephemeral public key is prepended to the encrypted payload)
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct EncryptionParams {
+    pub key_from: BlockHeight,
+    pub public_key: RawPubkey,
+}
+
+/// The chain parameters the validation enclave is bound to
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct ChainParams {
+    pub unbonding_period: u32,
+    /// the minimum fee of a transaction is computed from the size of its encoded `TxAux`
+    pub fee_policy: LinearFee,
+}
+
+/// The chain the validation enclave (and its storage) is bound to:
+/// sealed on the first initialization, later initializations need to match it
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct ChainGenesis {
+    pub chain_hex_id: u8,
+    pub genesis_app_hash: H256,
+    pub params: ChainParams,
+}
+
+/// derive the sealing key from the enclave's measurement (only the same enclave can unseal)
+pub const KEYPOLICY_MRENCLAVE: u16 = 0x0001;
+/// derive the sealing key from the enclave signer (enclaves from the same signer can unseal)
+pub const KEYPOLICY_MRSIGNER: u16 = 0x0002;
+
+/// The key request used for sealing data the host stores.
+/// The sealing key is always bound to the enclave's current ISVSVN
+/// (data sealed by an enclave can be unsealed by the ones with the same or higher ISVSVN).
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SealingPolicy {
+    /// `KEYPOLICY_MRENCLAVE`, `KEYPOLICY_MRSIGNER` or both
+    pub key_policy: u16,
+    /// which enclave attribute flags the key is bound to
+    pub attribute_flags_mask: u64,
+    /// which XFRM attributes the key is bound to
+    pub attribute_xfrm_mask: u64,
+    /// which MISCSELECT bits the key is bound to
+    pub misc_mask: u32,
+}
+
+impl SealingPolicy {
+    /// the SDK's default masks with the given key policy
+    pub fn with_key_policy(key_policy: u16) -> Self {
+        SealingPolicy {
+            key_policy,
+            attribute_flags_mask: 0xFF00_0000_0000_000B,
+            attribute_xfrm_mask: 0,
+            misc_mask: 0xF000_0000,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.key_policy != 0 && self.key_policy & !(KEYPOLICY_MRENCLAVE | KEYPOLICY_MRSIGNER) == 0
+    }
+}
+
+impl Default for SealingPolicy {
+    /// the same as `SgxSealedData::seal_data`
+    fn default() -> Self {
+        SealingPolicy::with_key_policy(KEYPOLICY_MRSIGNER)
+    }
+}
+
+/// The sealing policy recorded in the storage metadata
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SealingMetadata {
+    pub policy: SealingPolicy,
+    /// the ISVSVN of the enclave that sealed the data
+    pub isv_svn: u16,
+}
+
+/// How the validation enclave processes a transaction
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationMode {
+    /// mempool check (Tendermint's CheckTx): the transaction is only validated
+    Check = 0,
+    /// block delivery (Tendermint's DeliverTx): the sealed transaction is stored
+    /// and its view keys are added to the block filter
+    Deliver = 1,
+}
+
+impl ValidationMode {
+    pub fn from_u8(mode: u8) -> Option<Self> {
+        match mode {
+            0 => Some(ValidationMode::Check),
+            1 => Some(ValidationMode::Deliver),
+            _ => None,
+        }
+    }
+}
+
+/// Why the validation enclave refused to validate a transaction
+/// (as opposed to `chain_tx_validation::Error`, i.e. the transaction breaking a validation rule)
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnclaveRejection {
+    /// the request couldn't be decoded
+    RequestDecode,
+    /// the request is for a different network
+    WrongNetwork,
+    /// the enclave isn't bound to a chain genesis yet (or its chain state wasn't restored on CheckChain)
+    NotInitialized,
+    /// the request's chain parameters differ from the genesis ones
+    WrongChainParams,
+    /// the request is malformed (e.g. the inputs are missing or given for a transaction without inputs)
+    InvalidRequest,
+    /// the staked state account is missing
+    MissingAccount,
+    /// the payload couldn't be decrypted (e.g. unknown key or authentication failure)
+    PayloadDecryption,
+    /// the payload's nonce was already used for a different transaction
+    NonceReuse,
+    /// the decrypted payload isn't the expected transaction
+    PayloadDecode,
+    /// a sealed input couldn't be unsealed or doesn't belong to the input transaction
+    InputUnseal,
+    /// the decrypted transaction's id doesn't match the request
+    TxIdMismatch,
+    /// the decrypted transaction's inputs don't match the request
+    InputsMismatch,
+    /// the decrypted transaction's number of outputs doesn't match the request
+    OutputCountMismatch,
+    /// the witness is invalid (the address couldn't be recovered)
+    InvalidWitness,
+    /// the witness wasn't made by the staked state account
+    WitnessAddressMismatch,
+    /// an input was already spent
+    InputSpent,
+    /// the request's minimum fee differs from the one computed with the genesis fee policy
+    WrongFee,
+}
+
+/// What the validation enclave signs for every accepted transaction
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct ValidationStatement {
+    pub txid: TxId,
+    pub paid_fee: Fee,
+    pub account: Option<StakedState>,
+}
+
+impl ValidationStatement {
+    /// The signed message (the hash of the encoded statement, computed as the transaction ids)
+    pub fn hash(&self) -> H256 {
+        txid_hash(&self.encode())
+    }
+}
+
+/// The validation enclave's (compact) ECDSA signature of `ValidationStatement::hash`;
+/// the public key is in the report data of the enclave's report (see `ExtEnclaveRequest::GetValidationKey`)
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValidationSignature {
+    pub r: H256,
+    pub s: H256,
+}
+
+/// A transaction accepted by the validation enclave
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct ValidatedTx {
+    pub paid_fee: Fee,
+    /// the resulting staked state (of a deposit or a withdrawal), computed by the enclave
+    pub account: Option<StakedState>,
+    /// the sealed transaction to be stored (None in the check mode or if the transaction has no outputs)
+    pub sealed_tx: Option<Vec<u8>>,
+    /// the signature of the transaction id, the fee and the account
+    pub signature: ValidationSignature,
+}
+
+/// The result of an accepted transaction returned by tx-validation-app:
+/// the paid fee, the resulting staked state and the enclave's signature of them
+pub type AttestedResult = (Fee, Option<StakedState>, ValidationSignature);
+
+/// The validation enclave's reply to `IntraEnclaveRequest::ValidateTx`
+pub type ValidateTxResponse = Result<Result<ValidatedTx, Error>, EnclaveRejection>;
+
+/// Why a transaction was refused
+#[derive(Encode, Decode, Clone, Debug)]
+pub enum TxRejection {
+    /// the transaction isn't valid
+    Validation(Error),
+    /// the enclave refused the request
+    Enclave(EnclaveRejection),
+}
+
+impl From<TxRejection> for Error {
+    fn from(rejection: TxRejection) -> Self {
+        match rejection {
+            TxRejection::Validation(e) => e,
+            TxRejection::Enclave(_) => Error::EnclaveRejected,
+        }
+    }
+}
+
+/// Why the enclave refused the request of an encoded `EnclaveResponse::VerifyTx(Err(Error::EnclaveRejected))`:
+/// the rejection is appended after the response (which the standard decoding ignores)
+pub fn verify_tx_rejection(response: &[u8]) -> Option<EnclaveRejection> {
+    let mut input = response;
+    match EnclaveResponse::decode(&mut input) {
+        Ok(EnclaveResponse::VerifyTx(Err(Error::EnclaveRejected))) => {
+            EnclaveRejection::decode(&mut input).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Requests served by tx-validation-app in addition to `enclave_protocol::EnclaveRequest`.
+/// The variant indices start at 128, so that they don't overlap with the `EnclaveRequest` ones
+/// and both kinds of requests can be sent over the same connection.
+#[derive(Encode, Decode)]
+pub enum ExtEnclaveRequest {
+    /// the currently used transaction obfuscation key
+    #[codec(index = "128")]
+    GetEncryptionParams,
+    /// starts a new obfuscation key epoch (older keys are kept for decryption)
+    #[codec(index = "129")]
+    RotateEncryptionKey { key_from: BlockHeight },
+    /// binds the enclave to the genesis (to be sent on Tendermint's InitChain)
+    #[codec(index = "130")]
+    InitChain(ChainGenesis),
+    /// validates several transactions at once for block delivery (e.g. on block replays);
+    /// the responses are in the same order as the requests
+    #[codec(index = "131")]
+    VerifyTxBatch(Vec<VerifyTxRequest>),
+    /// validates the transaction for the mempool (it doesn't store anything or update the block filter),
+    /// like `EnclaveRequest::VerifyTx`, but the response includes the enclave's signature of the validation result
+    #[codec(index = "132")]
+    CheckTx(VerifyTxRequest),
+    /// the filters of the committed blocks from the `from` height (at most `limit` of them, the server may return fewer)
+    #[codec(index = "133")]
+    GetBlockFilters { from: BlockHeight, limit: u32 },
+    /// like `EnclaveRequest::EndBlock`, but the block filter is returned along with
+    /// the enclave's unspent outputs commitment (which should be the same on all validators)
+    #[codec(index = "134")]
+    EndBlock,
+    /// checks the stored audit log of the validation decisions (its hash chain up to the enclave's current head);
+    /// returns the number of the entries
+    #[codec(index = "135")]
+    VerifyAuditLog,
+    /// the public key the enclave signs the validation results with, along with the enclave's report
+    /// (the raw `sgx_report_t`, targeted at the enclave of the raw `sgx_target_info_t`, e.g. the quoting enclave)
+    /// whose report data starts with the compressed public key
+    #[codec(index = "136")]
+    GetValidationKey { target_info: Vec<u8> },
+    /// validates the transaction for block delivery (Tendermint's DeliverTx) -- the standard `EnclaveRequest::VerifyTx`
+    /// is sent on CheckTx as well, so it's only processed as a mempool check;
+    /// the response includes the enclave's signature of the validation result
+    #[codec(index = "137")]
+    VerifyTx(VerifyTxRequest),
+}
+
+/// Replies to `ExtEnclaveRequest`
+#[derive(Encode, Decode)]
+pub enum ExtEnclaveResponse {
+    GetEncryptionParams(Result<EncryptionParams, ()>),
+    RotateEncryptionKey(Result<EncryptionParams, ()>),
+    InitChain(Result<(), ()>),
+    VerifyTxBatch(Vec<Result<AttestedResult, TxRejection>>),
+    CheckTx(Result<AttestedResult, TxRejection>),
+    GetBlockFilters(Result<Vec<(BlockHeight, Box<[u8; 256]>)>, ()>),
+    EndBlock(Result<(Box<[u8; 256]>, H256), ()>),
+    VerifyAuditLog(Result<u64, ()>),
+    GetValidationKey(Result<(RawPubkey, Vec<u8>), ()>),
+    VerifyTx(Result<AttestedResult, TxRejection>),
+}
@@ -1,4 +1,6 @@
 pub mod enclave_u;
+/// the storage backends of the enclave apps
+pub mod storage;
 
 pub fn storage_path() -> String {
     match std::env::var("TX_ENCLAVE_STORAGE") {
@@ -9,3 +11,9 @@ pub fn storage_path() -> String {
 
 pub const META_KEYSPACE: &[u8] = b"meta";
 pub const TX_KEYSPACE: &[u8] = b"tx";
+pub const FILTER_KEYSPACE: &[u8] = b"filter";
+pub const SPENT_KEYSPACE: &[u8] = b"spent";
+pub const AUDIT_KEYSPACE: &[u8] = b"audit";
+
+/// the key (in `META_KEYSPACE`) under which the storage schema version is recorded
+pub const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
@@ -0,0 +1,147 @@
+use super::{Change, Entry, KeySpace, Storage, StorageError, StorageResult};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+
+type Entries = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// A keyspace kept in memory (its clones share the entries)
+#[derive(Clone, Default)]
+pub struct MemoryKeySpace {
+    entries: Arc<RwLock<Entries>>,
+}
+
+impl MemoryKeySpace {
+    fn entries_ptr(&self) -> *const RwLock<Entries> {
+        &*self.entries
+    }
+
+    fn write(&self) -> StorageResult<RwLockWriteGuard<Entries>> {
+        self.entries
+            .write()
+            .map_err(|_| StorageError("poisoned lock".to_owned()))
+    }
+
+    fn read_entries<F, R>(&self, f: F) -> StorageResult<R>
+    where
+        F: FnOnce(&Entries) -> R,
+    {
+        self.entries
+            .read()
+            .map(|entries| f(&entries))
+            .map_err(|_| StorageError("poisoned lock".to_owned()))
+    }
+}
+
+#[inline]
+fn apply(entries: &mut Entries, changes: &[Change]) {
+    for change in changes.iter() {
+        match change {
+            Change::Insert(key, value) => {
+                entries.insert(key.clone(), value.clone());
+            }
+            Change::Remove(key) => {
+                entries.remove(key);
+            }
+        }
+    }
+}
+
+impl KeySpace for MemoryKeySpace {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> StorageResult<Option<Vec<u8>>> {
+        self.read_entries(|entries| entries.get(key.as_ref()).cloned())
+    }
+
+    fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> StorageResult<()> {
+        self.write()?
+            .insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+        Ok(())
+    }
+
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.write()?.remove(key.as_ref()))
+    }
+
+    /// the entries are copied when it's called (later changes aren't iterated)
+    fn range_from<'a>(
+        &'a self,
+        from: &[u8],
+    ) -> Box<dyn Iterator<Item = StorageResult<Entry>> + 'a> {
+        match self.read_entries(|entries| {
+            entries
+                .range(from.to_vec()..)
+                .map(|(key, value)| Ok((key.clone(), value.clone())))
+                .collect::<Vec<_>>()
+        }) {
+            Ok(entries) => Box::new(entries.into_iter()),
+            Err(e) => Box::new(Some(Err(e)).into_iter()),
+        }
+    }
+
+    fn last(&self) -> StorageResult<Option<Entry>> {
+        self.read_entries(|entries| {
+            entries
+                .iter()
+                .next_back()
+                .map(|(key, value)| (key.clone(), value.clone()))
+        })
+    }
+
+    fn len(&self) -> StorageResult<usize> {
+        self.read_entries(|entries| entries.len())
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    fn apply_all(batch: &[(&Self, &[Change])]) -> StorageResult<()> {
+        // the locks are always taken in the same order (and only once per keyspace),
+        // so that concurrent calls don't deadlock
+        let mut keyspaces: Vec<&MemoryKeySpace> =
+            batch.iter().map(|(keyspace, _)| *keyspace).collect();
+        keyspaces.sort_by_key(|keyspace| keyspace.entries_ptr());
+        keyspaces.dedup_by_key(|keyspace| keyspace.entries_ptr());
+        let mut locked = Vec::with_capacity(keyspaces.len());
+        for keyspace in keyspaces.iter() {
+            locked.push((keyspace.entries_ptr(), keyspace.write()?));
+        }
+        for (keyspace, changes) in batch.iter() {
+            if let Some((_, entries)) = locked
+                .iter_mut()
+                .find(|(ptr, _)| *ptr == keyspace.entries_ptr())
+            {
+                apply(entries, changes);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A storage kept in memory (e.g. for tests) -- its clones share the keyspaces
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    keyspaces: Arc<RwLock<BTreeMap<Vec<u8>, MemoryKeySpace>>>,
+}
+
+impl Storage for MemoryStorage {
+    type KeySpace = MemoryKeySpace;
+
+    fn open_keyspace(&self, name: &[u8]) -> StorageResult<MemoryKeySpace> {
+        let mut keyspaces = self
+            .keyspaces
+            .write()
+            .map_err(|_| StorageError("poisoned lock".to_owned()))?;
+        Ok(keyspaces.entry(name.to_vec()).or_default().clone())
+    }
+
+    fn drop_keyspace(&self, name: &[u8]) -> StorageResult<()> {
+        let mut keyspaces = self
+            .keyspaces
+            .write()
+            .map_err(|_| StorageError("poisoned lock".to_owned()))?;
+        if let Some(keyspace) = keyspaces.remove(name) {
+            keyspace.write()?.clear();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+//! # Storage of the enclave apps
+//! The apps store the sealed transactions, the chain metadata, the launch tokens etc.
+//! in separate keyspaces of a key-value store; `Storage` and `KeySpace` abstract over the backend,
+//! so that it can be replaced (e.g. sled in the apps, an in-memory one in the tests,
+//! or RocksDB with a column family per keyspace).
+
+/// the in-memory storage (e.g. for tests)
+pub mod memory;
+/// the sled storage (a sled `Tree` per keyspace)
+#[cfg(feature = "sled")]
+pub mod sled;
+
+use crate::enclave_u::TOKEN_LEN;
+use log::{info, warn};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageError(pub String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// a (key, value) entry of a keyspace
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// A change applied (along with others) in one transaction
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+/// A keyspace: an ordered map of byte keys to byte values.
+/// The values inserted outside of transactions may only be durable after `flush`.
+pub trait KeySpace: Clone + Send + Sync + 'static {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> StorageResult<Option<Vec<u8>>>;
+
+    fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> StorageResult<bool> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> StorageResult<()>;
+
+    /// removes the entry and returns its value (if there was one)
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> StorageResult<Option<Vec<u8>>>;
+
+    /// the entries whose keys are greater than or equal to `from`, in the key order
+    fn range_from<'a>(&'a self, from: &[u8])
+        -> Box<dyn Iterator<Item = StorageResult<Entry>> + 'a>;
+
+    /// all the entries, in the key order
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = StorageResult<Entry>> + 'a> {
+        self.range_from(&[])
+    }
+
+    /// the entry with the greatest key
+    fn last(&self) -> StorageResult<Option<Entry>>;
+
+    fn len(&self) -> StorageResult<usize>;
+
+    fn is_empty(&self) -> StorageResult<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    fn flush(&self) -> StorageResult<()>;
+
+    /// applies the changes to their keyspaces (e.g. the transactions of a block, its spent outputs
+    /// and the chain metadata) in one durable transaction, i.e. either all of them are stored or none of them;
+    /// the keyspaces should be distinct
+    fn apply_all(batch: &[(&Self, &[Change])]) -> StorageResult<()>;
+}
+
+/// A key-value store with named keyspaces
+pub trait Storage: Clone + Send + Sync + 'static {
+    type KeySpace: KeySpace;
+
+    /// opens the keyspace (it's created if it doesn't exist)
+    fn open_keyspace(&self, name: &[u8]) -> StorageResult<Self::KeySpace>;
+
+    /// removes the keyspace and all its entries
+    fn drop_keyspace(&self, name: &[u8]) -> StorageResult<()>;
+}
+
+pub fn get_token<K: KeySpace>(metadb: &K, token_key: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+    metadb.get(token_key).map_err(|e| {
+        warn!("[-] Failed to read the launch token: {}", e);
+    })
+}
+
+pub fn get_token_arr<K: KeySpace>(
+    metadb: &K,
+    token_key: &[u8],
+) -> Result<Option<Box<[u8; TOKEN_LEN]>>, ()> {
+    match metadb.get(token_key) {
+        Ok(Some(ref tok)) if tok.len() != TOKEN_LEN => Err(()),
+        Ok(x) => Ok(x.map(|tok| {
+            let mut token = [0; TOKEN_LEN];
+            token.copy_from_slice(&tok);
+            Box::new(token)
+        })),
+        _ => Err(()),
+    }
+}
+
+pub fn store_token<K: KeySpace>(
+    metadb: &mut K,
+    token_key: &[u8],
+    launch_token: Vec<u8>,
+) -> Result<(), ()> {
+    match metadb.insert(token_key, launch_token) {
+        Ok(_) => {
+            info!("[+] Saved updated launch token!");
+            Ok(())
+        }
+        Err(_) => {
+            warn!("[-] Failed to save updated launch token!");
+            Err(())
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use super::{Change, Entry, KeySpace, Storage, StorageError, StorageResult};
+use sled::{Db, Transactional, Tree};
+
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+#[inline]
+fn to_entry(entry: sled::Result<(sled::IVec, sled::IVec)>) -> StorageResult<Entry> {
+    entry
+        .map(|(key, value)| (key.to_vec(), value.to_vec()))
+        .map_err(StorageError::from)
+}
+
+impl KeySpace for Tree {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> StorageResult<Option<Vec<u8>>> {
+        Ok(Tree::get(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> StorageResult<()> {
+        Tree::insert(self, key, value.as_ref())?;
+        Ok(())
+    }
+
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> StorageResult<Option<Vec<u8>>> {
+        Ok(Tree::remove(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn range_from<'a>(
+        &'a self,
+        from: &[u8],
+    ) -> Box<dyn Iterator<Item = StorageResult<Entry>> + 'a> {
+        Box::new(Tree::range(self, from.to_vec()..).map(to_entry))
+    }
+
+    fn last(&self) -> StorageResult<Option<Entry>> {
+        Tree::iter(self).next_back().map(to_entry).transpose()
+    }
+
+    fn len(&self) -> StorageResult<usize> {
+        Ok(Tree::len(self))
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        Tree::flush(self)?;
+        Ok(())
+    }
+
+    fn apply_all(batch: &[(&Tree, &[Change])]) -> StorageResult<()> {
+        let trees: Vec<&Tree> = batch.iter().map(|(tree, _)| *tree).collect();
+        trees
+            .as_slice()
+            .transaction(|tx_trees| {
+                for (tree, (_, changes)) in tx_trees.iter().zip(batch.iter()) {
+                    for change in changes.iter() {
+                        match change {
+                            Change::Insert(key, value) => {
+                                tree.insert(key.as_slice(), value.as_slice())?;
+                            }
+                            Change::Remove(key) => {
+                                tree.remove(key.as_slice())?;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e| StorageError(format!("transaction failed: {:?}", e)))?;
+        for tree in trees {
+            Tree::flush(tree)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for Db {
+    type KeySpace = Tree;
+
+    fn open_keyspace(&self, name: &[u8]) -> StorageResult<Tree> {
+        Ok(self.open_tree(name)?)
+    }
+
+    fn drop_keyspace(&self, name: &[u8]) -> StorageResult<()> {
+        self.drop_tree(name)?;
+        Ok(())
+    }
+}
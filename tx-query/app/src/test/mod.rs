@@ -3,13 +3,15 @@ use crate::enclave_u::run_server;
 use crate::enclave_u::ZMQ_SOCKET;
 use crate::start_enclave;
 use crate::TIMEOUT_SEC;
+use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead, Payload};
+use aes_gcm::Aes128Gcm;
 use chain_core::common::MerkleTree;
 use chain_core::init::address::RedeemAddress;
 use chain_core::init::coin::Coin;
 use chain_core::state::account::{
     StakedState, StakedStateAddress, StakedStateOpWitness, WithdrawUnbondedTx,
 };
-use chain_core::tx::fee::Fee;
+use chain_core::tx::fee::{Fee, LinearFee, Milli};
 use chain_core::tx::witness::tree::RawPubkey;
 use chain_core::tx::witness::EcdsaSignature;
 use chain_core::tx::PlainTxAux;
@@ -33,13 +35,17 @@ use client_core::cipher::DefaultTransactionObfuscation;
 use client_core::cipher::TransactionObfuscation;
 use enclave_protocol::FLAGS;
 use enclave_protocol::{EnclaveRequest, EnclaveResponse};
+use enclave_protocol_ext::{
+    ChainGenesis, ChainParams, EncryptionParams, ExtEnclaveRequest, ExtEnclaveResponse,
+};
 use enclave_u_common::enclave_u::{init_enclave, VALIDATION_TOKEN_KEY};
 use env_logger::{Builder, WriteStyle};
 use log::LevelFilter;
 use log::{debug, error, info, warn};
 use parity_scale_codec::{Decode, Encode};
 use secp256k1::{
-    key::PublicKey, key::SecretKey, schnorrsig::schnorr_sign, Message, Secp256k1, Signing,
+    ecdh::SharedSecret, key::PublicKey, key::SecretKey, schnorrsig::schnorr_sign, Message,
+    Secp256k1, Signing,
 };
 use sgx_types::sgx_status_t;
 use std::net::TcpListener;
@@ -70,6 +76,75 @@ fn get_account(account_address: &RedeemAddress) -> StakedState {
 
 const TEST_NETWORK_ID: u8 = 0xab;
 
+/// encrypts the payload to the tx-validation enclave's current obfuscation key
+/// (the ephemeral key is derived from the transaction id to keep the test deterministic)
+fn encrypt(
+    params: &EncryptionParams,
+    txid: &TxId,
+    plain: &PlainTxAux,
+    nonce: [u8; 12],
+) -> TxObfuscated {
+    let secp = Secp256k1::new();
+    let ephemeral_key = SecretKey::from_slice(&txid[..]).expect("32 bytes, within curve order");
+    let enclave_key = PublicKey::from_slice(params.public_key.as_bytes()).expect("public key");
+    let shared_secret = SharedSecret::new(&enclave_key, &ephemeral_key);
+    let cipher = Aes128Gcm::new(GenericArray::clone_from_slice(&shared_secret[..16]));
+    let encrypted = cipher
+        .encrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: &plain.encode(),
+                aad: &txid[..],
+            },
+        )
+        .expect("encryption");
+    let mut txpayload = PublicKey::from_secret_key(&secp, &ephemeral_key)
+        .serialize()
+        .to_vec();
+    txpayload.extend(encrypted);
+    TxObfuscated {
+        key_from: params.key_from,
+        nonce,
+        txpayload,
+    }
+}
+
+fn init_chain() {
+    ZMQ_SOCKET.with(|socket| {
+        let genesis = ChainGenesis {
+            chain_hex_id: TEST_NETWORK_ID,
+            genesis_app_hash: [0u8; 32],
+            params: ChainParams {
+                unbonding_period: 0,
+                fee_policy: LinearFee::new(Milli::new(0, 0), Milli::new(0, 0)),
+            },
+        };
+        let req = ExtEnclaveRequest::InitChain(genesis).encode();
+        socket.send(req, FLAGS).expect("request sending failed");
+        let msg = socket
+            .recv_bytes(FLAGS)
+            .expect("failed to receive a response");
+        match ExtEnclaveResponse::decode(&mut msg.as_slice()) {
+            Ok(ExtEnclaveResponse::InitChain(Ok(_))) => {}
+            _ => panic!("failed to init the chain"),
+        }
+    })
+}
+
+fn get_encryption_params() -> EncryptionParams {
+    ZMQ_SOCKET.with(|socket| {
+        let req = ExtEnclaveRequest::GetEncryptionParams.encode();
+        socket.send(req, FLAGS).expect("request sending failed");
+        let msg = socket
+            .recv_bytes(FLAGS)
+            .expect("failed to receive a response");
+        match ExtEnclaveResponse::decode(&mut msg.as_slice()) {
+            Ok(ExtEnclaveResponse::GetEncryptionParams(Ok(params))) => params,
+            _ => panic!("failed to get the encryption parameters"),
+        }
+    })
+}
+
 pub fn test_integration() {
     let mut builder = Builder::new();
     let validation_path =
@@ -118,6 +193,8 @@ pub fn test_integration() {
         }
     });
 
+    init_chain();
+    let params = get_encryption_params();
     let secp = Secp256k1::new();
     let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
     let public_key = PublicKey::from_secret_key(&secp, &secret_key);
@@ -140,11 +217,12 @@ pub fn test_integration() {
         txid: tx0.id(),
         no_of_outputs: tx0.outputs.len() as TxoIndex,
         witness: witness0,
-        payload: TxObfuscated {
-            key_from: 0,
-            nonce: [0u8; 12],
-            txpayload: PlainTxAux::WithdrawUnbondedStakeTx(tx0).encode(),
-        },
+        payload: encrypt(
+            &params,
+            &txid,
+            &PlainTxAux::WithdrawUnbondedStakeTx(tx0),
+            [0u8; 12],
+        ),
     };
     let account = get_account(&addr);
     let info = ChainInfo {
@@ -43,4 +43,9 @@ fn main() {
         "HW" => println!("cargo:rustc-link-lib=dylib=sgx_urts"),
         _ => println!("cargo:rustc-link-lib=dylib=sgx_urts"), // Treat undefined as HW
     }
+    // the platform services (for the monotonic counter)
+    match is_sim.as_ref() {
+        "SW" => println!("cargo:rustc-link-lib=dylib=sgx_uae_service_sim"),
+        _ => println!("cargo:rustc-link-lib=dylib=sgx_uae_service"),
+    }
 }
@@ -1,185 +1,1585 @@
 use sgx_types::*;
 
 use chain_core::common::H256;
-use chain_core::state::account::DepositBondTx;
-use chain_core::state::account::StakedState;
-use chain_core::tx::fee::Fee;
-use chain_core::tx::TxAux;
+use chain_core::state::tendermint::BlockHeight;
+use chain_core::tx::data::TxId;
+use chain_core::tx::witness::tree::RawPubkey;
 use chain_tx_validation::Error;
-use enclave_protocol::{IntraEnclaveRequest, IntraEnclaveResponse, IntraEnclaveResponseOk};
-use enclave_u_common::enclave_u::TOKEN_LEN;
-use log::{info, warn};
+use enclave_protocol::{
+    IntraEnclaveRequest, IntraEnclaveResponse, IntraEnclaveResponseOk, VerifyTxRequest,
+};
+use enclave_protocol_ext::{
+    AttestedResult, ChainGenesis, EncryptionParams, SealingMetadata, SealingPolicy, TxRejection,
+    ValidateTxResponse, ValidationMode, KEYPOLICY_MRENCLAVE, KEYPOLICY_MRSIGNER,
+};
+use enclave_u_common::storage::{Change, KeySpace};
+use log::{error, info, warn};
 use parity_scale_codec::{Decode, Encode};
-use sled::Tree;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
 use std::mem::size_of;
+use std::sync::Arc;
 
 extern "C" {
+    fn ecall_set_sealing_policy(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        policy: *const u8,
+        policy_len: usize,
+        isv_svn: *mut u16,
+    ) -> sgx_status_t;
+
+    fn ecall_reseal(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed_in: *const u8,
+        sealed_in_len: usize,
+        sealed_out: *mut u8,
+        sealed_out_len: u32,
+        sealed_out_written: *mut u32,
+    ) -> sgx_status_t;
+
     fn ecall_initchain(
         eid: sgx_enclave_id_t,
         retval: *mut sgx_status_t,
         chain_hex_id: u8,
+        genesis: *const u8,
+        genesis_len: usize,
+        sealed_genesis: *const u8,
+        sealed_genesis_len: usize,
+        sealed_out: *mut u8,
+        sealed_out_len: u32,
+        sealed_out_written: *mut u32,
+    ) -> sgx_status_t;
+
+    fn ecall_restore_obfuscation_keys(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed_keys: *const u8,
+        sealed_keys_len: usize,
+    ) -> sgx_status_t;
+
+    fn ecall_rotate_obfuscation_key(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        key_from: u64,
+        sealed_keys: *mut u8,
+        sealed_keys_len: u32,
+        sealed_keys_written: *mut u32,
+    ) -> sgx_status_t;
+
+    fn ecall_restore_signing_key(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed_key: *const u8,
+        sealed_key_len: usize,
+    ) -> sgx_status_t;
+
+    fn ecall_generate_signing_key(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed_key: *mut u8,
+        sealed_key_len: u32,
+        sealed_key_written: *mut u32,
+    ) -> sgx_status_t;
+
+    fn ecall_get_encryption_params(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        key_from: *mut u64,
+        public_key: *mut u8,
+    ) -> sgx_status_t;
+
+    fn ecall_check_checkpoint(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed_checkpoint: *const u8,
+        sealed_checkpoint_len: usize,
+        last_app_hash: *const u8,
+        last_app_hash_len: usize,
+    ) -> sgx_status_t;
+
+    fn ecall_prepare_commit(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        app_hash: *const u8,
+        sealed_checkpoint: *mut u8,
+        sealed_checkpoint_len: u32,
+        sealed_checkpoint_written: *mut u32,
+        height: *mut u64,
+        sealed_spent: *mut u8,
+        sealed_spent_len: u32,
+        sealed_spent_written: *mut u32,
+    ) -> sgx_status_t;
+
+    fn ecall_confirm_commit(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        app_hash: *const u8,
+    ) -> sgx_status_t;
+
+    fn ecall_confirm_delivery(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
+
+    fn ecall_abandon_block(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
+
+    fn ecall_restore_spent(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed_spent: *const u8,
+        sealed_spent_len: usize,
+    ) -> sgx_status_t;
+
+    fn ecall_check_spent(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
+
+    fn ecall_restore_audit_log(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed_entry: *const u8,
+        sealed_entry_len: usize,
+    ) -> sgx_status_t;
+
+    fn ecall_verify_audit_log(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed_entries: *const u8,
+        sealed_entries_len: usize,
+    ) -> sgx_status_t;
+
+    fn ecall_finish_audit_verification(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        verified: *mut u64,
+    ) -> sgx_status_t;
+
+    fn ecall_get_utxo_commitment(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        commitment: *mut u8,
+    ) -> sgx_status_t;
+
+    fn ecall_get_validation_key(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        target_info: *const sgx_target_info_t,
+        public_key: *mut u8,
+        report: *mut sgx_report_t,
+    ) -> sgx_status_t;
+
+    fn ecall_restore_filter(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        sealed_filter: *const u8,
+        sealed_filter_len: usize,
     ) -> sgx_status_t;
 
     fn ecall_check_tx(
         eid: sgx_enclave_id_t,
         retval: *mut sgx_status_t,
+        mode: u8,
         tx_request: *const u8,
         tx_request_len: usize,
         response_buf: *mut u8,
         response_len: u32,
+        response_written: *mut u32,
+        sealed_filter: *mut u8,
+        sealed_filter_len: u32,
+        sealed_filter_written: *mut u32,
+        sealed_audit: *mut u8,
+        sealed_audit_len: u32,
+        sealed_audit_written: *mut u32,
     ) -> sgx_status_t;
 
+    fn ecall_check_tx_batch(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        tx_requests: *const u8,
+        tx_requests_len: usize,
+        response_buf: *mut u8,
+        response_len: u32,
+        response_written: *mut u32,
+        sealed_filter: *mut u8,
+        sealed_filter_len: u32,
+        sealed_filter_written: *mut u32,
+        sealed_audit: *mut u8,
+        sealed_audit_len: u32,
+        sealed_audit_written: *mut u32,
+    ) -> sgx_status_t;
 }
 
-pub fn get_token(metadb: &Tree, token_key: &[u8]) -> Option<Vec<u8>> {
-    match metadb.get(token_key) {
-        Ok(x) => x.map(|tok| tok.to_vec()),
-        _ => None,
-    }
+/// metadb key under which the sealing policy (`SealingMetadata`) is recorded
+pub const SEALING_POLICY_KEY: &[u8] = b"tx-validation-enclave.sealing-policy";
+
+/// metadb key under which the progress of a sealing migration (the target `SealingMetadata`
+/// and the `MigrationStage`) is stored
+pub const SEALING_MIGRATION_KEY: &[u8] = b"tx-validation-enclave.sealing-migration";
+
+/// how many entries of a keyspace are resealed before the migration progress is recorded
+const MIGRATION_PROGRESS_INTERVAL: usize = 1000;
+
+/// The keyspace a sealing migration is resealing (and the last resealed key in it, if any);
+/// the keyspaces are resealed in this order
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+enum MigrationStage {
+    Txs(Option<Vec<u8>>),
+    Spent(Option<Vec<u8>>),
+    Audit(Option<Vec<u8>>),
+    /// the sealed chain metadata (the checkpoint, the block filter, the genesis, the obfuscation and signing keys)
+    Meta,
+}
+
+/// how many blocks' sealed spent outputs are passed to the enclave in one call
+const SPENT_RESTORE_BATCH: usize = 100;
+
+/// how many audit log entries are passed to the enclave in one call for verification
+const AUDIT_VERIFY_BATCH: usize = 100;
+
+/// a sealed audit log entry (its encoding and the sealing tag, besides the sealing header) usually fits in it
+/// (the buffer is grown if the enclave reports a larger length)
+const SEALED_AUDIT_ENTRY_LEN: usize = 512;
+
+/// metadb key under which the sealed chain genesis is stored
+pub const GENESIS_KEY: &[u8] = b"tx-validation-enclave.genesis";
+
+/// metadb key under which the sealed transaction obfuscation keys are stored
+pub const OBFUSCATION_KEYS_KEY: &[u8] = b"tx-validation-enclave.obfuscation-keys";
+
+/// metadb key under which the sealed validation signing key is stored
+pub const SIGNING_KEY_KEY: &[u8] = b"tx-validation-enclave.signing-key";
+
+/// metadb key under which the last app hash is stored
+pub const LAST_APP_HASH_KEY: &[u8] = b"last_apphash";
+
+/// metadb key under which the sealed checkpoint of the last committed block is stored
+pub const CHECKPOINT_KEY: &[u8] = b"last_checkpoint";
+
+/// metadb key under which the sealed block filter (after the last delivered transaction) is stored
+pub const BLOCK_FILTER_KEY: &[u8] = b"block_filter";
+
+/// the sealed block filter (its height and 256 bytes) usually fits in it
+/// (the buffer is grown if the enclave reports a larger length)
+const SEALED_FILTER_LEN: usize = 1024;
+
+/// Why the stored chain checkpoint was rejected
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheckpointError {
+    /// the sealed checkpoint is missing or doesn't unseal
+    Tampered,
+    /// the sealed checkpoint is older than the one the enclave last produced
+    RolledBack,
+    /// the checkpoint doesn't match the last app hash (i.e. the storage is behind or ahead of the chain)
+    AppHashMismatch,
+    IoError,
 }
 
-pub fn get_token_arr(metadb: &Tree, token_key: &[u8]) -> Result<Option<Box<[u8; TOKEN_LEN]>>, ()> {
-    match metadb.get(token_key) {
-        Ok(x) => Ok(x.map(|tok| {
-            let mut token = [0; TOKEN_LEN];
-            token.copy_from_slice(&tok);
-            Box::new(token)
-        })),
-        _ => Err(()),
+#[inline]
+fn parse_mask<T, F>(name: &str, default: T, from_str_radix: F) -> Result<T, String>
+where
+    F: Fn(&str, u32) -> Result<T, std::num::ParseIntError>,
+{
+    match std::env::var(name) {
+        Ok(mask) => from_str_radix(mask.trim_start_matches("0x"), 16)
+            .map_err(|e| format!("invalid {}: {}", name, e)),
+        Err(_) => Ok(default),
     }
 }
 
-pub fn store_token(metadb: &mut Tree, token_key: &[u8], launch_token: Vec<u8>) -> Result<(), ()> {
-    match metadb.insert(token_key, launch_token) {
-        Ok(_) => {
-            info!("[+] Saved updated launch token!");
+/// the sealing policy configured by the environment variables:
+/// * `TX_ENCLAVE_SEALING_POLICY`: "mrsigner" (the default), "mrenclave" or "mrenclave+mrsigner"
+/// * `TX_ENCLAVE_SEALING_FLAGS_MASK`, `TX_ENCLAVE_SEALING_XFRM_MASK`, `TX_ENCLAVE_SEALING_MISC_MASK`:
+///   hexadecimal attribute masks (the SDK defaults if not set)
+pub fn sealing_policy_from_env() -> Result<SealingPolicy, String> {
+    let key_policy = match std::env::var("TX_ENCLAVE_SEALING_POLICY")
+        .unwrap_or_else(|_| "mrsigner".to_owned())
+        .to_lowercase()
+        .as_str()
+    {
+        "mrsigner" => KEYPOLICY_MRSIGNER,
+        "mrenclave" => KEYPOLICY_MRENCLAVE,
+        "mrenclave+mrsigner" => KEYPOLICY_MRENCLAVE | KEYPOLICY_MRSIGNER,
+        x => {
+            return Err(format!("unknown sealing policy: {}", x));
+        }
+    };
+    let default = SealingPolicy::with_key_policy(key_policy);
+    Ok(SealingPolicy {
+        key_policy,
+        attribute_flags_mask: parse_mask(
+            "TX_ENCLAVE_SEALING_FLAGS_MASK",
+            default.attribute_flags_mask,
+            u64::from_str_radix,
+        )?,
+        attribute_xfrm_mask: parse_mask(
+            "TX_ENCLAVE_SEALING_XFRM_MASK",
+            default.attribute_xfrm_mask,
+            u64::from_str_radix,
+        )?,
+        misc_mask: parse_mask(
+            "TX_ENCLAVE_SEALING_MISC_MASK",
+            default.misc_mask,
+            u32::from_str_radix,
+        )?,
+    })
+}
+
+/// sets the enclave's sealing policy and records it in metadb on the first start;
+/// if the storage was sealed with a different policy or by an enclave with a lower ISVSVN
+/// (or a previous migration was interrupted), all the sealed data is migrated to the current sealing key
+pub fn init_sealing_policy<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    policy: SealingPolicy,
+    txdb: &mut K,
+    metadb: &mut K,
+    spentdb: &mut K,
+    auditdb: &mut K,
+) -> Result<(), ()> {
+    let policy_buf = policy.encode();
+    let mut isv_svn: u16 = 0;
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        ecall_set_sealing_policy(
+            eid,
+            &mut retval,
+            policy_buf.as_ptr(),
+            policy_buf.len(),
+            &mut isv_svn,
+        )
+    };
+    if retval != sgx_status_t::SGX_SUCCESS || result != retval {
+        error!(
+            "sealing policy rejected by the enclave: {} {}",
+            result, retval
+        );
+        return Err(());
+    }
+    let current = SealingMetadata { policy, isv_svn };
+    let migration_pending = metadb.contains_key(SEALING_MIGRATION_KEY).map_err(|_| ())?;
+    match metadb.get(SEALING_POLICY_KEY) {
+        Ok(Some(recorded)) => match SealingMetadata::decode(&mut recorded.as_slice()) {
+            Ok(recorded) if recorded == current && !migration_pending => Ok(()),
+            Ok(recorded) if recorded.isv_svn > isv_svn => {
+                error!(
+                    "the storage was sealed by an enclave with a higher ISVSVN ({}, the current one is {})",
+                    recorded.isv_svn, isv_svn
+                );
+                Err(())
+            }
+            Ok(recorded) => {
+                info!(
+                    "[+] Migrating the sealed data from {:?} to {:?}",
+                    recorded, current
+                );
+                migrate_sealed_data(eid, current, txdb, metadb, spentdb, auditdb)
+            }
+            Err(_) => {
+                error!("invalid sealing policy metadata");
+                Err(())
+            }
+        },
+        Ok(None) => {
+            info!("[+] Recording the sealing policy: {:?}", current);
+            if metadb.insert(SEALING_POLICY_KEY, current.encode()).is_err()
+                || metadb.flush().is_err()
+            {
+                error!("failed to record the sealing policy");
+                return Err(());
+            }
             Ok(())
         }
+        Err(_) => Err(()),
+    }
+}
+
+/// unseals the blob (with the key request it was sealed with) and seals it with the current sealing key
+fn reseal(eid: sgx_enclave_id_t, sealed: &[u8]) -> Result<Vec<u8>, ()> {
+    call_with_response_buf(
+        sealed.len(),
+        |retval, sealed_out, sealed_out_written| unsafe {
+            ecall_reseal(
+                eid,
+                retval,
+                sealed.as_ptr(),
+                sealed.len(),
+                sealed_out.as_mut_ptr(),
+                sealed_out.len() as u32,
+                sealed_out_written,
+            )
+        },
+    )
+}
+
+#[inline]
+fn reseal_stored<K: KeySpace>(eid: sgx_enclave_id_t, db: &mut K, key: &[u8]) -> Result<(), ()> {
+    if let Some(sealed) = db.get(key).map_err(|_| ())? {
+        let resealed = reseal(eid, &sealed)?;
+        db.insert(key, resealed).map_err(|_| ())?;
+    }
+    Ok(())
+}
+
+fn record_migration_progress<K: KeySpace>(
+    metadb: &mut K,
+    target: SealingMetadata,
+    stage: &MigrationStage,
+) -> Result<(), ()> {
+    metadb
+        .insert(SEALING_MIGRATION_KEY, (target, stage).encode())
+        .map_err(|_| ())?;
+    metadb.flush().map_err(|_| ())
+}
+
+/// reseals the entries of the keyspace after `last_key` (in the key order; the keys `is_sealed` rejects are skipped)
+/// and records the progress (the `stage` of the last resealed key) every `MIGRATION_PROGRESS_INTERVAL` entries --
+/// returns the number of resealed entries
+fn reseal_keyspace<K, S, F>(
+    eid: sgx_enclave_id_t,
+    target: SealingMetadata,
+    db: &mut K,
+    metadb: &mut K,
+    last_key: Option<Vec<u8>>,
+    stage: S,
+    is_sealed: F,
+) -> Result<usize, ()>
+where
+    K: KeySpace,
+    S: Fn(Option<Vec<u8>>) -> MigrationStage,
+    F: Fn(&[u8]) -> bool,
+{
+    let start = last_key.clone().unwrap_or_default();
+    let mut resealed = 0usize;
+    for entry in db.range_from(start.as_slice()) {
+        let (key, sealed) = entry.map_err(|_| ())?;
+        if !is_sealed(&key) || last_key.as_ref().map(|x| x.as_slice()) == Some(key.as_slice()) {
+            continue;
+        }
+        let resealed_entry = reseal(eid, &sealed).map_err(|_| {
+            error!("failed to reseal {}", hex::encode(&key));
+        })?;
+        db.insert(&key, resealed_entry).map_err(|_| ())?;
+        resealed += 1;
+        if resealed % MIGRATION_PROGRESS_INTERVAL == 0 {
+            db.flush().map_err(|_| ())?;
+            record_migration_progress(metadb, target, &stage(Some(key.to_vec())))?;
+            info!("[+] Resealed {} entries", resealed);
+        }
+    }
+    db.flush().map_err(|_| ())?;
+    Ok(resealed)
+}
+
+/// reseals the transactions (in the order of their ids), the spent outputs, the audit log, the checkpoint, the block filter,
+/// the genesis, the obfuscation and signing keys; the progress in each keyspace is recorded in metadb,
+/// so that an interrupted migration is resumed on the next start (resealing the rest again is harmless).
+/// Note that the data sealed with the MRENCLAVE policy can only be unsealed by the same enclave,
+/// i.e. it needs to be migrated to the MRSIGNER policy before the enclave is upgraded.
+fn migrate_sealed_data<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    target: SealingMetadata,
+    txdb: &mut K,
+    metadb: &mut K,
+    spentdb: &mut K,
+    auditdb: &mut K,
+) -> Result<(), ()> {
+    let mut stage = match metadb.get(SEALING_MIGRATION_KEY) {
+        Ok(Some(progress)) => {
+            match <(SealingMetadata, MigrationStage)>::decode(&mut progress.as_slice()) {
+                Ok((in_progress, stage)) if in_progress == target => {
+                    info!(
+                        "[+] Resuming the interrupted sealing migration ({:?})",
+                        stage
+                    );
+                    stage
+                }
+                _ => {
+                    error!("a migration to a different sealing policy was interrupted");
+                    return Err(());
+                }
+            }
+        }
+        Ok(None) => {
+            let stage = MigrationStage::Txs(None);
+            record_migration_progress(metadb, target, &stage)?;
+            stage
+        }
+        Err(_) => {
+            return Err(());
+        }
+    };
+    let mut resealed = 0usize;
+    loop {
+        stage = match stage {
+            MigrationStage::Txs(last_txid) => {
+                // the transaction ids are 32 bytes (any other keys are skipped)
+                resealed += reseal_keyspace(
+                    eid,
+                    target,
+                    txdb,
+                    metadb,
+                    last_txid,
+                    MigrationStage::Txs,
+                    |txid| txid.len() == 32,
+                )?;
+                MigrationStage::Spent(None)
+            }
+            MigrationStage::Spent(last_height) => {
+                resealed += reseal_keyspace(
+                    eid,
+                    target,
+                    spentdb,
+                    metadb,
+                    last_height,
+                    MigrationStage::Spent,
+                    |_| true,
+                )?;
+                MigrationStage::Audit(None)
+            }
+            MigrationStage::Audit(last_seq) => {
+                resealed += reseal_keyspace(
+                    eid,
+                    target,
+                    auditdb,
+                    metadb,
+                    last_seq,
+                    MigrationStage::Audit,
+                    |_| true,
+                )?;
+                MigrationStage::Meta
+            }
+            MigrationStage::Meta => {
+                reseal_stored(eid, metadb, CHECKPOINT_KEY)?;
+                reseal_stored(eid, metadb, BLOCK_FILTER_KEY)?;
+                reseal_stored(eid, metadb, GENESIS_KEY)?;
+                reseal_stored(eid, metadb, OBFUSCATION_KEYS_KEY)?;
+                reseal_stored(eid, metadb, SIGNING_KEY_KEY)?;
+                break;
+            }
+        };
+        record_migration_progress(metadb, target, &stage)?;
+    }
+    metadb
+        .insert(SEALING_POLICY_KEY, target.encode())
+        .map_err(|_| ())?;
+    metadb.remove(SEALING_MIGRATION_KEY).map_err(|_| ())?;
+    metadb.flush().map_err(|_| ())?;
+    info!("[+] Sealing migration finished ({} entries)", resealed);
+    Ok(())
+}
+
+/// checks the network id and binds the enclave to the genesis:
+/// on the first initialization with `genesis`, the sealed genesis is stored in metadb;
+/// afterwards, the stored sealed genesis is passed to the enclave which checks it against the request
+pub fn init_chain<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    chain_hex_id: u8,
+    genesis: Option<&ChainGenesis>,
+    metadb: &mut K,
+) -> Result<(), ()> {
+    let sealed_genesis = match metadb.get(GENESIS_KEY) {
+        Ok(x) => x.map(|sealed| sealed.to_vec()).unwrap_or_default(),
         Err(_) => {
-            warn!("[-] Failed to save updated launch token!");
-            Err(())
+            return Err(());
         }
+    };
+    let genesis = genesis.map(|g| g.encode()).unwrap_or_default();
+    let mut sealed_out: Vec<u8> = vec![0u8; size_of::<sgx_sealed_data_t>() + 1024];
+    let mut sealed_out_written: u32 = 0;
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        ecall_initchain(
+            eid,
+            &mut retval,
+            chain_hex_id,
+            genesis.as_ptr(),
+            genesis.len(),
+            sealed_genesis.as_ptr(),
+            sealed_genesis.len(),
+            sealed_out.as_mut_ptr(),
+            sealed_out.len() as u32,
+            &mut sealed_out_written,
+        )
+    };
+    if retval != sgx_status_t::SGX_SUCCESS || result != retval {
+        error!(
+            "chain initialization rejected by the enclave: {} {}",
+            result, retval
+        );
+        return Err(());
     }
+    if sealed_out_written > 0 {
+        info!("[+] Storing the sealed genesis");
+        sealed_out.truncate(sealed_out_written as usize);
+        if metadb.insert(GENESIS_KEY, sealed_out).is_err() || metadb.flush().is_err() {
+            error!("failed to store the sealed genesis");
+            return Err(());
+        }
+    }
+    Ok(())
 }
 
-pub fn check_initchain(
+pub fn check_initchain<K: KeySpace>(
     eid: sgx_enclave_id_t,
     chain_hex_id: u8,
     last_app_hash: Option<H256>,
+    metadb: &mut K,
 ) -> Result<(), Option<H256>> {
+    if last_app_hash.is_some() && !metadb.contains_key(GENESIS_KEY).unwrap_or(false) {
+        error!("the storage contains chain data, but no sealed genesis");
+        return Err(last_app_hash);
+    }
+    init_chain(eid, chain_hex_id, None, metadb).map_err(|_| last_app_hash)
+}
+
+/// checks the sealed checkpoint stored in metadb against the last app hash reported on CheckChain
+pub fn check_checkpoint<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    last_app_hash: Option<H256>,
+    metadb: &K,
+) -> Result<(), CheckpointError> {
+    let sealed_checkpoint = match metadb.get(CHECKPOINT_KEY) {
+        Ok(x) => x.map(|sealed| sealed.to_vec()),
+        Err(_) => {
+            return Err(CheckpointError::IoError);
+        }
+    };
+    if sealed_checkpoint.is_none() && last_app_hash.is_some() {
+        error!("the storage contains chain data, but no sealed checkpoint");
+        return Err(CheckpointError::Tampered);
+    }
+    let sealed_checkpoint = sealed_checkpoint.unwrap_or_default();
+    let last_app_hash = last_app_hash.map(|h| h.to_vec()).unwrap_or_default();
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        ecall_check_checkpoint(
+            eid,
+            &mut retval,
+            sealed_checkpoint.as_ptr(),
+            sealed_checkpoint.len(),
+            last_app_hash.as_ptr(),
+            last_app_hash.len(),
+        )
+    };
+    if result != sgx_status_t::SGX_SUCCESS {
+        error!("failed to check the chain checkpoint: {}", result);
+        return Err(CheckpointError::IoError);
+    }
+    match retval {
+        sgx_status_t::SGX_SUCCESS => Ok(()),
+        sgx_status_t::SGX_ERROR_MAC_MISMATCH => {
+            error!("the sealed chain checkpoint was tampered with");
+            Err(CheckpointError::Tampered)
+        }
+        sgx_status_t::SGX_ERROR_INVALID_STATE => {
+            error!("the sealed chain checkpoint was rolled back");
+            Err(CheckpointError::RolledBack)
+        }
+        _ => {
+            warn!("the sealed chain checkpoint doesn't match the last app hash");
+            Err(CheckpointError::AppHashMismatch)
+        }
+    }
+}
+
+/// the key under which the block filter (in filterdb) or the spent outputs (in spentdb) of `height` are stored
+/// (big-endian, so that they're iterated in the height order)
+fn height_key(height: BlockHeight) -> [u8; 8] {
+    height.to_be_bytes()
+}
+
+/// The sealed transactions delivered in the current block -- they're only stored in txdb when the block
+/// is committed (in the same transaction as the app hash), so they're discarded if it's never committed
+#[derive(Default)]
+pub struct StagedBlock {
+    txs: BTreeMap<TxId, Vec<u8>>,
+}
+
+impl StagedBlock {
+    /// the sealed transaction delivered in the current block
+    pub fn get(&self, txid: &TxId) -> Option<&[u8]> {
+        self.txs.get(txid).map(|sealed_tx| sealed_tx.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    fn insert(&mut self, txid: TxId, sealed_tx: Vec<u8>) {
+        self.txs.insert(txid, sealed_tx);
+    }
+
+    /// discards the delivered transactions (e.g. when the block is abandoned)
+    pub fn clear(&mut self) {
+        self.txs.clear();
+    }
+}
+
+/// The sealed audit log entries of the validation decisions since the last committed block -- they're stored
+/// with the next committed block (in the same transaction as the checkpoint which includes the audit log head),
+/// so that storing them can't fail after the enclave appended them. The clones share the entries
+/// (the mempool checks and the block deliveries are appended to the same log).
+#[derive(Default, Clone)]
+pub struct StagedAudit {
+    entries: Arc<Mutex<BTreeMap<u64, Vec<u8>>>>,
+}
+
+impl StagedAudit {
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+
+    /// stages the sealed audit log entries written back by a transaction validation (if any)
+    fn stage(&self, sealed_audit: &[u8]) -> Result<(), ()> {
+        if sealed_audit.is_empty() {
+            return Ok(());
+        }
+        let entries: Vec<(u64, Vec<u8>)> =
+            Decode::decode(&mut &sealed_audit[..]).map_err(|_| {
+                error!("invalid audit log entries");
+            })?;
+        self.entries.lock().extend(entries);
+        Ok(())
+    }
+
+    /// the staged entries (in the sequence order)
+    fn entries(&self) -> Vec<(u64, Vec<u8>)> {
+        self.entries
+            .lock()
+            .iter()
+            .map(|(seq, sealed)| (*seq, sealed.clone()))
+            .collect()
+    }
+
+    /// discards the entries up to `last_seq` (once they're stored)
+    fn remove_stored(&self, last_seq: u64) {
+        let mut entries = self.entries.lock();
+        *entries = entries.split_off(&(last_seq + 1));
+    }
+
+    fn last(&self) -> Option<Vec<u8>> {
+        self.entries.lock().values().next_back().cloned()
+    }
+}
+
+/// prepares the enclave's next checkpoint with the committed app hash and stores it and the app hash in metadb
+/// in one transaction with the transactions staged in the block (in txdb), the block's filter (returned on EndBlock, in filterdb)
+/// and the sealed outputs spent in the block (in spentdb), the latter two under the committed height (which is returned),
+/// and the staged audit log entries (in auditdb); the enclave only adopts the checkpoint once they're stored
+/// (and the staged transactions and audit log entries are then cleared).
+/// No transactions may be validated meanwhile, as the checkpoint includes the audit log head.
+pub fn commit_block<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    app_hash: &H256,
+    block_filter: Option<&[u8; 256]>,
+    staged: &mut StagedBlock,
+    audit: &StagedAudit,
+    txdb: &mut K,
+    metadb: &mut K,
+    filterdb: &mut K,
+    spentdb: &mut K,
+    auditdb: &K,
+) -> Result<BlockHeight, ()> {
+    let mut sealed_checkpoint: Vec<u8> = vec![0u8; size_of::<sgx_sealed_data_t>() + 1024];
+    let mut sealed_spent: Vec<u8> = vec![0u8; size_of::<sgx_sealed_data_t>() + 4096];
+    let mut height: BlockHeight = 0;
+    loop {
+        let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+        let mut sealed_checkpoint_written: u32 = 0;
+        let mut sealed_spent_written: u32 = 0;
+        let result = unsafe {
+            ecall_prepare_commit(
+                eid,
+                &mut retval,
+                app_hash.as_ptr(),
+                sealed_checkpoint.as_mut_ptr(),
+                sealed_checkpoint.len() as u32,
+                &mut sealed_checkpoint_written,
+                &mut height,
+                sealed_spent.as_mut_ptr(),
+                sealed_spent.len() as u32,
+                &mut sealed_spent_written,
+            )
+        };
+        if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+            sealed_checkpoint.truncate(sealed_checkpoint_written as usize);
+            sealed_spent.truncate(sealed_spent_written as usize);
+            break;
+        } else if result == sgx_status_t::SGX_SUCCESS
+            && ((sealed_checkpoint_written as usize) > sealed_checkpoint.len()
+                || (sealed_spent_written as usize) > sealed_spent.len())
+        {
+            sealed_checkpoint = vec![
+                0u8;
+                sealed_checkpoint
+                    .len()
+                    .max(sealed_checkpoint_written as usize)
+            ];
+            sealed_spent = vec![0u8; sealed_spent.len().max(sealed_spent_written as usize)];
+        } else {
+            error!(
+                "failed to prepare the chain checkpoint: {} {}",
+                result, retval
+            );
+            return Err(());
+        }
+    }
+    let spent_changes: Vec<Change> = if sealed_spent.is_empty() {
+        vec![]
+    } else {
+        vec![Change::Insert(height_key(height).to_vec(), sealed_spent)]
+    };
+    let filter_changes: Vec<Change> = block_filter
+        .map(|filter| Change::Insert(height_key(height).to_vec(), filter.to_vec()))
+        .into_iter()
+        .collect();
+    let tx_changes: Vec<Change> = staged
+        .txs
+        .iter()
+        .map(|(txid, sealed_tx)| Change::Insert(txid.to_vec(), sealed_tx.clone()))
+        .collect();
+    let audit_entries = audit.entries();
+    let audit_changes: Vec<Change> = audit_entries
+        .iter()
+        .map(|(seq, sealed)| Change::Insert(audit_key(*seq).to_vec(), sealed.clone()))
+        .collect();
+    let meta_changes = [
+        Change::Insert(LAST_APP_HASH_KEY.to_vec(), app_hash.to_vec()),
+        Change::Insert(CHECKPOINT_KEY.to_vec(), sealed_checkpoint),
+    ];
+    if K::apply_all(&[
+        (&*txdb, &tx_changes[..]),
+        (&*metadb, &meta_changes[..]),
+        (&*spentdb, &spent_changes[..]),
+        (&*filterdb, &filter_changes[..]),
+        (auditdb, &audit_changes[..]),
+    ])
+    .is_err()
+    {
+        error!("failed to store the block and the chain checkpoint");
+        return Err(());
+    }
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe { ecall_confirm_commit(eid, &mut retval, app_hash.as_ptr()) };
+    if retval != sgx_status_t::SGX_SUCCESS || result != retval {
+        error!(
+            "failed to confirm the chain checkpoint: {} {}",
+            result, retval
+        );
+        return Err(());
+    }
+    staged.clear();
+    if let Some((last_seq, _)) = audit_entries.last() {
+        audit.remove_stored(*last_seq);
+    }
+    Ok(height)
+}
+
+/// discards the current block's changes in the enclave (its filter, spent and created outputs)
+/// and the staged transactions and sealed filter of the block
+pub fn abandon_block<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    staged: &mut StagedBlock,
+    metadb: &mut K,
+) -> Result<(), ()> {
     let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
-    let result = unsafe { ecall_initchain(eid, &mut retval, chain_hex_id) };
+    let result = unsafe { ecall_abandon_block(eid, &mut retval) };
+    if retval != sgx_status_t::SGX_SUCCESS || result != retval {
+        error!("failed to abandon the block: {} {}", result, retval);
+        return Err(());
+    }
+    staged.clear();
+    metadb.remove(BLOCK_FILTER_KEY).map(|_| ()).map_err(|_| {
+        error!("failed to remove the block filter");
+    })
+}
+
+/// the stored block filters from the `from` height (at most `limit` of them); heights committed
+/// before the filters were stored are skipped
+pub fn get_block_filters<K: KeySpace>(
+    filterdb: &K,
+    from: BlockHeight,
+    limit: u32,
+) -> Result<Vec<(BlockHeight, Box<[u8; 256]>)>, ()> {
+    let mut result = Vec::new();
+    for item in filterdb.range_from(&height_key(from)).take(limit as usize) {
+        let (key, value) = item.map_err(|_| ())?;
+        if key.len() != 8 || value.len() != 256 {
+            error!("invalid block filter entry");
+            return Err(());
+        }
+        let mut height = [0u8; 8];
+        height.copy_from_slice(&key);
+        let mut filter = Box::new([0u8; 256]);
+        filter.copy_from_slice(&value);
+        result.push((BlockHeight::from_be_bytes(height), filter));
+    }
+    Ok(result)
+}
+
+/// passes the stored spent outputs to the enclave (which checks them against the checkpoint) --
+/// needs to be called after `check_checkpoint`
+pub fn restore_spent_set<K: KeySpace>(eid: sgx_enclave_id_t, spentdb: &K) -> Result<(), ()> {
+    let mut batch: Vec<Vec<u8>> = Vec::with_capacity(SPENT_RESTORE_BATCH);
+    for entry in spentdb.iter() {
+        let (_, sealed) = entry.map_err(|_| ())?;
+        batch.push(sealed.to_vec());
+        if batch.len() == SPENT_RESTORE_BATCH {
+            restore_spent(eid, &batch)?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        restore_spent(eid, &batch)?;
+    }
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe { ecall_check_spent(eid, &mut retval) };
     if retval == sgx_status_t::SGX_SUCCESS && result == retval {
         Ok(())
     } else {
-        Err(last_app_hash)
+        error!(
+            "the stored spent outputs were rejected: {} {}",
+            result, retval
+        );
+        Err(())
     }
 }
 
-pub fn end_block(
+fn restore_spent(eid: sgx_enclave_id_t, sealed_blocks: &[Vec<u8>]) -> Result<(), ()> {
+    let sealed_spent = sealed_blocks.encode();
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result =
+        unsafe { ecall_restore_spent(eid, &mut retval, sealed_spent.as_ptr(), sealed_spent.len()) };
+    if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+        Ok(())
+    } else {
+        error!("failed to restore the spent outputs: {} {}", result, retval);
+        Err(())
+    }
+}
+
+/// the key under which the audit log entry `seq` is stored in auditdb
+/// (big-endian, so that they're iterated in the sequence order)
+fn audit_key(seq: u64) -> [u8; 8] {
+    seq.to_be_bytes()
+}
+
+/// continues the enclave's audit log from the last staged or stored entry -- needs to be called after `check_checkpoint`
+pub fn restore_audit_log<K: KeySpace>(
     eid: sgx_enclave_id_t,
-    request: IntraEnclaveRequest,
-) -> Result<Box<[u8; 256]>, ()> {
-    let request_buf: Vec<u8> = request.encode();
-    let mut response_buf: Vec<u8> = vec![0u8; 260];
+    auditdb: &K,
+    audit: &StagedAudit,
+) -> Result<(), ()> {
+    let last_entry = match audit.last() {
+        Some(sealed) => sealed,
+        None => match auditdb.last() {
+            Ok(Some((_, sealed))) => sealed,
+            Ok(None) => Vec::new(),
+            Err(_) => {
+                return Err(());
+            }
+        },
+    };
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result =
+        unsafe { ecall_restore_audit_log(eid, &mut retval, last_entry.as_ptr(), last_entry.len()) };
+    if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+        Ok(())
+    } else {
+        error!("failed to restore the audit log: {} {}", result, retval);
+        Err(())
+    }
+}
+
+/// passes all the stored (and then the staged) audit log entries to the enclave which checks the hash chain
+/// (up to its current head) -- returns the number of the verified entries
+pub fn verify_audit_log<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    auditdb: &K,
+    audit: &StagedAudit,
+) -> Result<u64, ()> {
+    let mut batch: Vec<Vec<u8>> = Vec::with_capacity(AUDIT_VERIFY_BATCH);
+    let stored = auditdb
+        .iter()
+        .map(|entry| entry.map(|(_, sealed)| sealed).map_err(|_| ()));
+    let staged = audit.entries().into_iter().map(|(_, sealed)| Ok(sealed));
+    for entry in stored.chain(staged) {
+        batch.push(entry?);
+        if batch.len() == AUDIT_VERIFY_BATCH {
+            verify_audit_entries(eid, &batch)?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        verify_audit_entries(eid, &batch)?;
+    }
+    let mut verified: u64 = 0;
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe { ecall_finish_audit_verification(eid, &mut retval, &mut verified) };
+    if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+        Ok(verified)
+    } else {
+        error!("the stored audit log was rejected: {} {}", result, retval);
+        Err(())
+    }
+}
+
+fn verify_audit_entries(eid: sgx_enclave_id_t, sealed_entries: &[Vec<u8>]) -> Result<(), ()> {
+    let sealed = sealed_entries.encode();
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe { ecall_verify_audit_log(eid, &mut retval, sealed.as_ptr(), sealed.len()) };
+    if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+        Ok(())
+    } else {
+        error!("the stored audit log was rejected: {} {}", result, retval);
+        Err(())
+    }
+}
+
+/// restores the stored block filter (if any) -- needs to be called after `check_checkpoint`
+pub fn restore_block_filter<K: KeySpace>(eid: sgx_enclave_id_t, metadb: &K) -> Result<(), ()> {
+    let sealed_filter = match metadb.get(BLOCK_FILTER_KEY) {
+        Ok(Some(sealed_filter)) => sealed_filter,
+        Ok(None) => {
+            return Ok(());
+        }
+        Err(_) => {
+            return Err(());
+        }
+    };
     let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
-    let response_slice = &mut response_buf[..];
     let result = unsafe {
-        ecall_check_tx(
+        ecall_restore_filter(
             eid,
             &mut retval,
-            request_buf.as_ptr(),
-            request_buf.len(),
-            response_slice.as_mut_ptr(),
-            response_buf.len() as u32,
+            sealed_filter.as_ptr(),
+            sealed_filter.len(),
         )
     };
     if retval == sgx_status_t::SGX_SUCCESS && result == retval {
-        let response = IntraEnclaveResponse::decode(&mut response_buf.as_slice());
-        match response {
-            Ok(Ok(IntraEnclaveResponseOk::EndBlock(filter))) => Ok(filter),
-            _ => Err(()),
+        Ok(())
+    } else {
+        error!("failed to restore the block filter: {} {}", result, retval);
+        Err(())
+    }
+}
+
+/// stores the sealed block filter written back by a transaction validation (if any)
+fn store_block_filter<K: KeySpace>(sealed_filter: &[u8], metadb: &mut K) -> Result<(), ()> {
+    if sealed_filter.is_empty() {
+        return Ok(());
+    }
+    metadb
+        .insert(BLOCK_FILTER_KEY, sealed_filter)
+        .map(|_| ())
+        .map_err(|_| {
+            error!("failed to store the block filter");
+        })
+}
+
+/// stores the sealed block filter written back by a block delivery (if any, i.e. a transaction was delivered)
+/// and then lets the enclave add the delivery to the current block
+fn confirm_delivery<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    sealed_filter: &[u8],
+    metadb: &mut K,
+) -> Result<(), ()> {
+    if sealed_filter.is_empty() {
+        return Ok(());
+    }
+    store_block_filter(sealed_filter, metadb)?;
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe { ecall_confirm_delivery(eid, &mut retval) };
+    if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+        Ok(())
+    } else {
+        error!("failed to confirm the delivery: {} {}", result, retval);
+        Err(())
+    }
+}
+
+/// restores the stored obfuscation keys or (on the first start) generates the initial ones
+pub fn init_obfuscation_keys<K: KeySpace>(eid: sgx_enclave_id_t, metadb: &mut K) -> Result<(), ()> {
+    match metadb.get(OBFUSCATION_KEYS_KEY) {
+        Ok(Some(sealed_keys)) => {
+            let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+            let result = unsafe {
+                ecall_restore_obfuscation_keys(
+                    eid,
+                    &mut retval,
+                    sealed_keys.as_ptr(),
+                    sealed_keys.len(),
+                )
+            };
+            if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+                Ok(())
+            } else {
+                error!(
+                    "failed to restore the obfuscation keys: {} {}",
+                    result, retval
+                );
+                Err(())
+            }
+        }
+        Ok(None) => {
+            info!("[+] Generating the initial obfuscation key");
+            rotate_obfuscation_key(eid, 0, metadb).map(|_| ())
+        }
+        Err(_) => Err(()),
+    }
+}
+
+/// starts a new obfuscation key epoch (from the `key_from` block height) and stores the sealed keys
+pub fn rotate_obfuscation_key<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    key_from: BlockHeight,
+    metadb: &mut K,
+) -> Result<EncryptionParams, ()> {
+    let mut sealed_keys: Vec<u8> = vec![0u8; size_of::<sgx_sealed_data_t>() + 1024];
+    loop {
+        let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+        let mut sealed_keys_written: u32 = 0;
+        let result = unsafe {
+            ecall_rotate_obfuscation_key(
+                eid,
+                &mut retval,
+                key_from,
+                sealed_keys.as_mut_ptr(),
+                sealed_keys.len() as u32,
+                &mut sealed_keys_written,
+            )
+        };
+        if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+            sealed_keys.truncate(sealed_keys_written as usize);
+            break;
+        } else if result == sgx_status_t::SGX_SUCCESS
+            && (sealed_keys_written as usize) > sealed_keys.len()
+        {
+            sealed_keys = vec![0u8; sealed_keys_written as usize];
+        } else {
+            error!(
+                "failed to rotate the obfuscation key: {} {}",
+                result, retval
+            );
+            return Err(());
+        }
+    }
+    if metadb.insert(OBFUSCATION_KEYS_KEY, sealed_keys).is_err() || metadb.flush().is_err() {
+        error!("failed to store the sealed obfuscation keys");
+        return Err(());
+    }
+    get_encryption_params(eid)
+}
+
+/// restores the stored validation signing key or (on the first start) generates it and stores it sealed
+pub fn init_signing_key<K: KeySpace>(eid: sgx_enclave_id_t, metadb: &mut K) -> Result<(), ()> {
+    match metadb.get(SIGNING_KEY_KEY) {
+        Ok(Some(sealed_key)) => {
+            let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+            let result = unsafe {
+                ecall_restore_signing_key(eid, &mut retval, sealed_key.as_ptr(), sealed_key.len())
+            };
+            if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+                Ok(())
+            } else {
+                error!(
+                    "failed to restore the validation signing key: {} {}",
+                    result, retval
+                );
+                Err(())
+            }
+        }
+        Ok(None) => {
+            info!("[+] Generating the validation signing key");
+            let sealed_key = call_with_response_buf(
+                size_of::<sgx_sealed_data_t>() + 128,
+                |retval, sealed_key, sealed_key_written| unsafe {
+                    ecall_generate_signing_key(
+                        eid,
+                        retval,
+                        sealed_key.as_mut_ptr(),
+                        sealed_key.len() as u32,
+                        sealed_key_written,
+                    )
+                },
+            )?;
+            if metadb.insert(SIGNING_KEY_KEY, sealed_key).is_err() || metadb.flush().is_err() {
+                error!("failed to store the sealed validation signing key");
+                return Err(());
+            }
+            Ok(())
         }
+        Err(_) => Err(()),
+    }
+}
+
+pub fn get_encryption_params(eid: sgx_enclave_id_t) -> Result<EncryptionParams, ()> {
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let mut key_from: u64 = 0;
+    let mut public_key = [0u8; 33];
+    let result = unsafe {
+        ecall_get_encryption_params(eid, &mut retval, &mut key_from, public_key.as_mut_ptr())
+    };
+    if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+        Ok(EncryptionParams {
+            key_from,
+            public_key: RawPubkey::from(public_key),
+        })
     } else {
         Err(())
     }
 }
 
-pub fn check_tx(
+/// the data written back by a transaction validation
+struct ValidationBufs {
+    response: Vec<u8>,
+    /// the sealed block filter (empty if no transaction was delivered)
+    sealed_filter: Vec<u8>,
+    /// the sealed audit log entries of the decisions
+    sealed_audit: Vec<u8>,
+}
+
+/// calls a transaction validation ecall that writes back its response, the sealed block filter and the sealed
+/// audit log entries (and sets their lengths); if any of them doesn't fit in its buffer, the call is retried
+/// with buffers of the reported lengths (the enclave doesn't record anything until all of them fit)
+fn call_with_validation_bufs<F>(
+    response_len: usize,
+    audit_len: usize,
+    mut ecall: F,
+) -> Result<ValidationBufs, ()>
+where
+    F: FnMut(
+        &mut sgx_status_t,
+        &mut [u8],
+        &mut u32,
+        &mut [u8],
+        &mut u32,
+        &mut [u8],
+        &mut u32,
+    ) -> sgx_status_t,
+{
+    let mut bufs = ValidationBufs {
+        response: vec![0u8; response_len],
+        sealed_filter: vec![0u8; size_of::<sgx_sealed_data_t>() + SEALED_FILTER_LEN],
+        sealed_audit: vec![0u8; audit_len],
+    };
+    loop {
+        let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+        let mut response_written: u32 = 0;
+        let mut sealed_filter_written: u32 = 0;
+        let mut sealed_audit_written: u32 = 0;
+        let result = ecall(
+            &mut retval,
+            &mut bufs.response[..],
+            &mut response_written,
+            &mut bufs.sealed_filter[..],
+            &mut sealed_filter_written,
+            &mut bufs.sealed_audit[..],
+            &mut sealed_audit_written,
+        );
+        let written = [
+            (response_written as usize, bufs.response.len()),
+            (sealed_filter_written as usize, bufs.sealed_filter.len()),
+            (sealed_audit_written as usize, bufs.sealed_audit.len()),
+        ];
+        if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+            bufs.response.truncate(response_written as usize);
+            bufs.sealed_filter.truncate(sealed_filter_written as usize);
+            bufs.sealed_audit.truncate(sealed_audit_written as usize);
+            return Ok(bufs);
+        } else if result == sgx_status_t::SGX_SUCCESS
+            && written.iter().any(|(written, len)| written > len)
+        {
+            bufs.response = vec![0u8; bufs.response.len().max(response_written as usize)];
+            bufs.sealed_filter =
+                vec![0u8; bufs.sealed_filter.len().max(sealed_filter_written as usize)];
+            bufs.sealed_audit =
+                vec![0u8; bufs.sealed_audit.len().max(sealed_audit_written as usize)];
+        } else {
+            error!("enclave call failed: {} {}", result, retval);
+            return Err(());
+        }
+    }
+}
+
+/// calls an ecall that writes back its response (and sets the response length);
+/// if the response doesn't fit in the buffer, the call is retried with a buffer of the reported length
+fn call_with_response_buf<F>(initial_len: usize, mut ecall: F) -> Result<Vec<u8>, ()>
+where
+    F: FnMut(&mut sgx_status_t, &mut [u8], &mut u32) -> sgx_status_t,
+{
+    let mut response_buf: Vec<u8> = vec![0u8; initial_len];
+    loop {
+        let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+        let mut response_written: u32 = 0;
+        let result = ecall(&mut retval, &mut response_buf[..], &mut response_written);
+        if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+            response_buf.truncate(response_written as usize);
+            return Ok(response_buf);
+        } else if result == sgx_status_t::SGX_SUCCESS
+            && (response_written as usize) > response_buf.len()
+        {
+            response_buf = vec![0u8; response_written as usize];
+        } else {
+            error!("enclave call failed: {} {}", result, retval);
+            return Err(());
+        }
+    }
+}
+
+/// returns the block filter (and resets it) and the unspent outputs commitment
+pub fn end_block(
     eid: sgx_enclave_id_t,
     request: IntraEnclaveRequest,
-    txdb: &mut Tree,
-) -> Result<(Fee, Option<StakedState>), Error> {
+) -> Result<(Box<[u8; 256]>, H256), ()> {
     let request_buf: Vec<u8> = request.encode();
-    let response_len = size_of::<sgx_sealed_data_t>() + request_buf.len();
-    let mut response_buf: Vec<u8> = vec![0u8; response_len];
+    let response_buf =
+        call_with_response_buf(260, |retval, response_buf, response_written| unsafe {
+            ecall_check_tx(
+                eid,
+                retval,
+                ValidationMode::Deliver as u8,
+                request_buf.as_ptr(),
+                request_buf.len(),
+                response_buf.as_mut_ptr(),
+                response_buf.len() as u32,
+                response_written,
+                std::ptr::null_mut(),
+                0,
+                &mut 0,
+                std::ptr::null_mut(),
+                0,
+                &mut 0,
+            )
+        })?;
+    let response = IntraEnclaveResponse::decode(&mut response_buf.as_slice());
+    let filter = match response {
+        Ok(Ok(IntraEnclaveResponseOk::EndBlock(filter))) => filter,
+        _ => {
+            return Err(());
+        }
+    };
+    let mut commitment: H256 = [0u8; 32];
+    let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe { ecall_get_utxo_commitment(eid, &mut retval, commitment.as_mut_ptr()) };
+    if retval == sgx_status_t::SGX_SUCCESS && result == retval {
+        Ok((filter, commitment))
+    } else {
+        error!(
+            "failed to get the unspent outputs commitment: {} {}",
+            result, retval
+        );
+        Err(())
+    }
+}
+
+/// returns the public key the validation results are signed with and the enclave's report (the raw `sgx_report_t`)
+/// for `target_info` (the raw `sgx_target_info_t`), whose report data starts with the public key
+pub fn get_validation_key(
+    eid: sgx_enclave_id_t,
+    target_info: &[u8],
+) -> Result<(RawPubkey, Vec<u8>), ()> {
+    if target_info.len() != size_of::<sgx_target_info_t>() {
+        error!("invalid target info");
+        return Err(());
+    }
+    let target_info: sgx_target_info_t =
+        unsafe { std::ptr::read_unaligned(target_info.as_ptr() as *const sgx_target_info_t) };
+    let mut public_key = [0u8; 33];
+    let mut report = sgx_report_t::default();
     let mut retval: sgx_status_t = sgx_status_t::SGX_SUCCESS;
-    let response_slice = &mut response_buf[..];
     let result = unsafe {
-        ecall_check_tx(
+        ecall_get_validation_key(
             eid,
             &mut retval,
-            request_buf.as_ptr(),
-            request_buf.len(),
-            response_slice.as_mut_ptr(),
-            response_buf.len() as u32,
+            &target_info,
+            public_key.as_mut_ptr(),
+            &mut report,
         )
     };
     if retval == sgx_status_t::SGX_SUCCESS && result == retval {
-        let response = IntraEnclaveResponse::decode(&mut response_buf.as_slice());
-        match (request, response) {
-            (
-                IntraEnclaveRequest::ValidateTx { request, .. },
-                Ok(Ok(IntraEnclaveResponseOk::TxWithOutputs {
-                    paid_fee,
-                    sealed_tx,
-                })),
-            ) => {
-                let _ = txdb
-                    .insert(&request.tx.tx_id(), sealed_tx)
-                    .map_err(|_| Error::IoError)?;
-                if let Some(mut account) = request.account {
-                    account.withdraw();
-                    Ok((paid_fee, Some(account)))
-                } else {
-                    Ok((paid_fee, None))
-                }
+        let raw_report = unsafe {
+            std::slice::from_raw_parts(
+                &report as *const sgx_report_t as *const u8,
+                size_of::<sgx_report_t>(),
+            )
+        }
+        .to_vec();
+        Ok((RawPubkey::from(public_key), raw_report))
+    } else {
+        error!("failed to get the validation key: {} {}", result, retval);
+        Err(())
+    }
+}
+
+/// validates the transaction -- in the deliver mode, the sealed transaction is staged in the current block
+/// (once the sealed block filter is stored in metadb); the audit log entry of the decision is staged in `audit`
+pub fn check_tx<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    request: IntraEnclaveRequest,
+    mode: ValidationMode,
+    metadb: &mut K,
+    staged: &mut StagedBlock,
+    audit: &StagedAudit,
+) -> Result<AttestedResult, TxRejection> {
+    let request_buf: Vec<u8> = request.encode();
+    let response_len = size_of::<sgx_sealed_data_t>() + request_buf.len();
+    let bufs = call_with_validation_bufs(
+        response_len,
+        size_of::<sgx_sealed_data_t>() + SEALED_AUDIT_ENTRY_LEN,
+        |retval,
+         response_buf,
+         response_written,
+         sealed_filter,
+         sealed_filter_written,
+         sealed_audit,
+         sealed_audit_written| unsafe {
+            ecall_check_tx(
+                eid,
+                retval,
+                mode as u8,
+                request_buf.as_ptr(),
+                request_buf.len(),
+                response_buf.as_mut_ptr(),
+                response_buf.len() as u32,
+                response_written,
+                sealed_filter.as_mut_ptr(),
+                sealed_filter.len() as u32,
+                sealed_filter_written,
+                sealed_audit.as_mut_ptr(),
+                sealed_audit.len() as u32,
+                sealed_audit_written,
+            )
+        },
+    )
+    .map_err(|_| TxRejection::Validation(Error::EnclaveRejected))?;
+    audit
+        .stage(&bufs.sealed_audit)
+        .map_err(|_| TxRejection::Validation(Error::IoError))?;
+    let response = ValidateTxResponse::decode(&mut bufs.response.as_slice());
+    match (request, response) {
+        (IntraEnclaveRequest::ValidateTx { request, .. }, Ok(response)) => {
+            // the sealed transaction is only staged once the delivery is in the enclave's block
+            confirm_delivery(eid, &bufs.sealed_filter, metadb)
+                .map_err(|_| TxRejection::Validation(Error::IoError))?;
+            process_response(request, response, staged)
+        }
+        (_, _) => Err(TxRejection::Validation(Error::EnclaveRejected)),
+    }
+}
+
+/// validates the transactions for block delivery in one enclave call -- `requests` need to be `IntraEnclaveRequest::ValidateTx`
+/// and mustn't spend each other's outputs (as the sealed outputs are only staged after the call)
+pub fn check_tx_batch<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    requests: Vec<IntraEnclaveRequest>,
+    metadb: &mut K,
+    staged: &mut StagedBlock,
+    audit: &StagedAudit,
+) -> Vec<Result<AttestedResult, TxRejection>> {
+    let request_buf: Vec<u8> = requests.encode();
+    let response_len = size_of::<sgx_sealed_data_t>() * requests.len() + request_buf.len();
+    let bufs = call_with_validation_bufs(
+        response_len,
+        (size_of::<sgx_sealed_data_t>() + SEALED_AUDIT_ENTRY_LEN) * requests.len(),
+        |retval,
+         response_buf,
+         response_written,
+         sealed_filter,
+         sealed_filter_written,
+         sealed_audit,
+         sealed_audit_written| unsafe {
+            ecall_check_tx_batch(
+                eid,
+                retval,
+                request_buf.as_ptr(),
+                request_buf.len(),
+                response_buf.as_mut_ptr(),
+                response_buf.len() as u32,
+                response_written,
+                sealed_filter.as_mut_ptr(),
+                sealed_filter.len() as u32,
+                sealed_filter_written,
+                sealed_audit.as_mut_ptr(),
+                sealed_audit.len() as u32,
+                sealed_audit_written,
+            )
+        },
+    )
+    .ok();
+    let responses: Option<Vec<ValidateTxResponse>> = bufs
+        .as_ref()
+        .and_then(|bufs| Decode::decode(&mut bufs.response.as_slice()).ok())
+        .filter(|responses: &Vec<ValidateTxResponse>| responses.len() == requests.len());
+    let (sealed_filter, sealed_audit) = match bufs {
+        Some(bufs) => (bufs.sealed_filter, bufs.sealed_audit),
+        None => (vec![], vec![]),
+    };
+    if audit.stage(&sealed_audit).is_err() {
+        return requests
+            .iter()
+            .map(|_| Err(TxRejection::Validation(Error::IoError)))
+            .collect();
+    }
+    match responses {
+        Some(responses) => {
+            // the sealed transactions are only staged once the delivery is in the enclave's block
+            if confirm_delivery(eid, &sealed_filter, metadb).is_err() {
+                return requests
+                    .iter()
+                    .map(|_| Err(TxRejection::Validation(Error::IoError)))
+                    .collect();
             }
-            (
-                IntraEnclaveRequest::ValidateTx { request, .. },
-                Ok(Ok(IntraEnclaveResponseOk::DepositStakeTx { input_coins })),
-            ) => {
-                let deposit_amount =
-                    (input_coins - request.info.min_fee_computed.to_coin()).expect("init");
-                let account = match (request.account, request.tx) {
-                    (Some(mut a), _) => {
-                        a.deposit(deposit_amount);
-                        Some(a)
+            requests
+                .into_iter()
+                .zip(responses.into_iter())
+                .map(|(request, response)| match request {
+                    IntraEnclaveRequest::ValidateTx { request, .. } => {
+                        process_response(request, response, staged)
                     }
-                    (
-                        None,
-                        TxAux::DepositStakeTx {
-                            tx:
-                                DepositBondTx {
-                                    to_staked_account, ..
-                                },
-                            ..
-                        },
-                    ) => Some(StakedState::new_init(
-                        deposit_amount,
-                        request.info.previous_block_time,
-                        to_staked_account,
-                        true,
-                    )),
-                    (_, _) => unreachable!("one shouldn't call this with other variants"),
-                };
-                let fee = request.info.min_fee_computed;
-                Ok((fee, account))
+                    _ => Err(TxRejection::Validation(Error::EnclaveRejected)),
+                })
+                .collect()
+        }
+        None => {
+            error!("batch validation failed");
+            requests
+                .iter()
+                .map(|_| Err(TxRejection::Validation(Error::EnclaveRejected)))
+                .collect()
+        }
+    }
+}
+
+/// stages the sealed transaction (if any, i.e. only in the deliver mode) -- the resulting account state
+/// is computed by the enclave
+fn process_response(
+    request: Box<VerifyTxRequest>,
+    response: ValidateTxResponse,
+    staged: &mut StagedBlock,
+) -> Result<AttestedResult, TxRejection> {
+    let response = match response {
+        Ok(response) => response,
+        Err(rejection) => {
+            warn!(
+                "transaction {} refused by the enclave: {:?}",
+                hex::encode(&request.tx.tx_id()),
+                rejection
+            );
+            return Err(TxRejection::Enclave(rejection));
+        }
+    };
+    match response {
+        Ok(validated) => {
+            if let Some(sealed_tx) = validated.sealed_tx {
+                staged.insert(request.tx.tx_id(), sealed_tx);
             }
-            (_, Ok(Err(e))) => Err(e),
-            (_, _) => Err(Error::EnclaveRejected),
+            Ok((validated.paid_fee, validated.account, validated.signature))
         }
-    } else {
-        Err(Error::EnclaveRejected)
+        Err(e) => Err(TxRejection::Validation(e)),
     }
 }
@@ -1,12 +1,18 @@
 mod enclave_u;
+mod migrations;
 mod server;
 #[cfg(feature = "sgx-test")]
 mod test;
 
-use crate::enclave_u::{get_token, store_token};
+use crate::enclave_u::{
+    init_obfuscation_keys, init_sealing_policy, init_signing_key, sealing_policy_from_env,
+};
 use crate::server::TxValidationServer;
 use enclave_u_common::enclave_u::{init_enclave, VALIDATION_TOKEN_KEY};
-use enclave_u_common::{storage_path, META_KEYSPACE, TX_KEYSPACE};
+use enclave_u_common::storage::{get_token, store_token, Storage};
+use enclave_u_common::{
+    storage_path, AUDIT_KEYSPACE, FILTER_KEYSPACE, META_KEYSPACE, SPENT_KEYSPACE, TX_KEYSPACE,
+};
 use log::{error, info};
 use sled::Db;
 use std::env;
@@ -25,14 +31,40 @@ fn main() {
         error!("Please provide the ZMQ connection string (e.g. \"tcp://127.0.0.1:25933\") as the first argument");
         return;
     }
+    let sealing_policy = match sealing_policy_from_env() {
+        Ok(policy) => policy,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
     let db = Db::open(storage_path()).expect("failed to open a storage path");
+    if migrations::migrate(&db).is_err() {
+        error!("[-] Failed to migrate the storage");
+        return;
+    }
     let mut metadb = db
-        .open_tree(META_KEYSPACE)
+        .open_keyspace(META_KEYSPACE)
         .expect("failed to open a meta keyspace");
-    let txdb = db
-        .open_tree(TX_KEYSPACE)
+    let mut txdb = db
+        .open_keyspace(TX_KEYSPACE)
         .expect("failed to open a tx keyspace");
-    let token = get_token(&metadb, VALIDATION_TOKEN_KEY);
+    let filterdb = db
+        .open_keyspace(FILTER_KEYSPACE)
+        .expect("failed to open a filter keyspace");
+    let mut spentdb = db
+        .open_keyspace(SPENT_KEYSPACE)
+        .expect("failed to open a spent keyspace");
+    let mut auditdb = db
+        .open_keyspace(AUDIT_KEYSPACE)
+        .expect("failed to open an audit keyspace");
+    let token = match get_token(&metadb, VALIDATION_TOKEN_KEY) {
+        Ok(token) => token,
+        Err(_) => {
+            error!("[-] Failed to read the launch token");
+            return;
+        }
+    };
     let enclave = match init_enclave(true, token) {
         (Ok(r), new_token) => {
             info!("[+] Init Enclave Successful {}!", r.geteid());
@@ -46,10 +78,32 @@ fn main() {
             return;
         }
     };
+    if init_sealing_policy(
+        enclave.geteid(),
+        sealing_policy,
+        &mut txdb,
+        &mut metadb,
+        &mut spentdb,
+        &mut auditdb,
+    )
+    .is_err()
+    {
+        error!("[-] Failed to set the sealing policy");
+        return;
+    }
+    if init_obfuscation_keys(enclave.geteid(), &mut metadb).is_err() {
+        error!("[-] Failed to initialize the transaction obfuscation keys");
+        return;
+    }
+    if init_signing_key(enclave.geteid(), &mut metadb).is_err() {
+        error!("[-] Failed to initialize the validation signing key");
+        return;
+    }
 
     let child_t = thread::spawn(move || {
-        let mut server = TxValidationServer::new(&args[1], enclave, txdb, metadb)
-            .expect("could not start a zmq server");
+        let mut server =
+            TxValidationServer::new(&args[1], enclave, txdb, metadb, filterdb, spentdb, auditdb)
+                .expect("could not start a zmq server");
         info!("starting zmq server");
         server.execute()
     });
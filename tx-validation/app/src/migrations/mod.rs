@@ -0,0 +1,86 @@
+//! # Storage schema migrations
+//! The storage schema version is recorded in the meta keyspace; on startup (before the enclave
+//! touches the stored data), the migrations from the recorded version to the current one are run
+//! in order, and the version is recorded after each of them.
+
+use crate::enclave_u::{BLOCK_FILTER_KEY, CHECKPOINT_KEY, LAST_APP_HASH_KEY};
+use enclave_u_common::storage::{Change, KeySpace, Storage};
+use enclave_u_common::{META_KEYSPACE, SCHEMA_VERSION_KEY, TX_KEYSPACE};
+use log::{error, info};
+use parity_scale_codec::{Decode, Encode};
+
+/// the storage schema version of this build
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A migration from the previous schema version -- it needs to be idempotent,
+/// as it's run again if it's interrupted before the new version is recorded
+type Migration<S> = fn(&S) -> Result<(), ()>;
+
+/// the migrations to each schema version (the one to version `n` is the `n - 1`th)
+fn migrations<S: Storage>() -> [(&'static str, Migration<S>); SCHEMA_VERSION as usize] {
+    [(
+        "move the chain metadata out of the tx keyspace",
+        move_chain_metadata,
+    )]
+}
+
+/// the recorded schema version (0 if there's none, i.e. the storage is new or predates the versioning)
+pub fn stored_version<K: KeySpace>(metadb: &K) -> Result<u32, ()> {
+    match metadb.get(SCHEMA_VERSION_KEY) {
+        Ok(Some(version)) => u32::decode(&mut version.as_slice()).map_err(|_| {
+            error!("invalid storage schema version");
+        }),
+        Ok(None) => Ok(0),
+        Err(_) => Err(()),
+    }
+}
+
+/// runs the migrations from the recorded schema version to `SCHEMA_VERSION`;
+/// fails if the storage was migrated by a newer version
+pub fn migrate<S: Storage>(storage: &S) -> Result<(), ()> {
+    let metadb = storage.open_keyspace(META_KEYSPACE).map_err(|_| ())?;
+    let stored = stored_version(&metadb)?;
+    if stored > SCHEMA_VERSION {
+        error!(
+            "the storage schema version {} is newer than the supported one ({})",
+            stored, SCHEMA_VERSION
+        );
+        return Err(());
+    }
+    for (i, (description, migration)) in migrations::<S>().iter().enumerate().skip(stored as usize)
+    {
+        let version = i as u32 + 1;
+        info!(
+            "[+] Migrating the storage to schema version {}: {}",
+            version, description
+        );
+        migration(storage)?;
+        if metadb.insert(SCHEMA_VERSION_KEY, version.encode()).is_err() || metadb.flush().is_err() {
+            error!("failed to record the storage schema version");
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+/// version 1: the last app hash, the sealed checkpoint and the sealed block filter used to be stored
+/// in the tx keyspace (next to the transactions) -- they're moved to the meta keyspace
+fn move_chain_metadata<S: Storage>(storage: &S) -> Result<(), ()> {
+    let txdb = storage.open_keyspace(TX_KEYSPACE).map_err(|_| ())?;
+    let metadb = storage.open_keyspace(META_KEYSPACE).map_err(|_| ())?;
+    let mut removed = Vec::new();
+    let mut inserted = Vec::new();
+    for key in [LAST_APP_HASH_KEY, CHECKPOINT_KEY, BLOCK_FILTER_KEY].iter() {
+        if let Some(value) = txdb.get(*key).map_err(|_| ())? {
+            removed.push(Change::Remove(key.to_vec()));
+            inserted.push(Change::Insert(key.to_vec(), value));
+        }
+    }
+    if removed.is_empty() {
+        return Ok(());
+    }
+    <S::KeySpace as KeySpace>::apply_all(&[(&txdb, &removed[..]), (&metadb, &inserted[..])])
+        .map_err(|e| {
+            error!("failed to move the chain metadata: {}", e);
+        })
+}
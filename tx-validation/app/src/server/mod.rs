@@ -1,154 +1,705 @@
-use crate::enclave_u::{check_initchain, check_tx, end_block, get_token_arr, store_token};
+use crate::enclave_u::{
+    abandon_block, check_checkpoint, check_initchain, check_tx, check_tx_batch, commit_block,
+    end_block, get_block_filters, get_encryption_params, get_validation_key, init_chain,
+    restore_audit_log, restore_block_filter, restore_spent_set, rotate_obfuscation_key,
+    verify_audit_log, CheckpointError, StagedAudit, StagedBlock, LAST_APP_HASH_KEY,
+};
+use chain_core::common::H256;
 use chain_core::state::account::DepositBondTx;
 use chain_core::tx::data::TxId;
 use chain_core::tx::TxAux;
+use chain_tx_validation::Error as TxError;
 use enclave_protocol::IntraEnclaveRequest;
-use enclave_protocol::{is_basic_valid_tx_request, EnclaveRequest, EnclaveResponse, FLAGS};
-use log::{debug, info};
+use enclave_protocol::{
+    is_basic_valid_tx_request, EnclaveRequest, EnclaveResponse, VerifyTxRequest, FLAGS,
+};
+use enclave_protocol_ext::{
+    AttestedResult, EnclaveRejection, ExtEnclaveRequest, ExtEnclaveResponse, TxRejection,
+    ValidationMode,
+};
+use enclave_u_common::storage::{get_token_arr, store_token, KeySpace};
+use log::{debug, info, warn};
 use parity_scale_codec::{Decode, Encode};
+use parking_lot::RwLock;
+use sgx_types::sgx_enclave_id_t;
 use sgx_urts::SgxEnclave;
-use sled::Tree;
-use zmq::{Context, Error, Socket, REP};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::thread;
+use zmq::{Context, Error, Socket, DEALER, POLLIN, REP, ROUTER};
 
+/// the most block filters returned in one reply
+const MAX_BLOCK_FILTERS: u32 = 1000;
+/// the number of workers serving read-only lookups
+const READER_WORKERS: usize = 4;
+/// the number of workers validating transactions for the mempool
+/// (together with the mutating worker, they need to fit in the enclave's TCSNum)
+const CHECK_WORKERS: usize = 4;
+/// where the worker handling state-changing requests (in order) is connected
+const MUTATING_ENDPOINT: &str = "inproc://tx-validation-mutating";
+/// where the workers handling read-only lookups are connected
+const READERS_ENDPOINT: &str = "inproc://tx-validation-readers";
+/// where the workers validating transactions for the mempool are connected
+const CHECKERS_ENDPOINT: &str = "inproc://tx-validation-checkers";
+
+/// Accepts requests from several clients on a ROUTER socket and dispatches them:
+/// read-only lookups (sealed transactions, launch tokens, block filters) are served
+/// in parallel by a pool of reader workers, mempool checks are validated in parallel
+/// by a pool of check workers (the enclave has several TCS), while everything else goes
+/// to a single worker, so that state-changing requests are processed in the order
+/// they were received.
 pub struct TxValidationServer {
-    socket: Socket,
-    enclave: SgxEnclave,
-    txdb: Tree,
-    metadb: Tree,
+    frontend: Socket,
+    mutating: Socket,
+    readers: Socket,
+    checkers: Socket,
 }
 
 impl TxValidationServer {
-    pub fn new(
+    pub fn new<K: KeySpace>(
         connection_str: &str,
         enclave: SgxEnclave,
-        txdb: Tree,
-        metadb: Tree,
+        txdb: K,
+        metadb: K,
+        filterdb: K,
+        spentdb: K,
+        auditdb: K,
     ) -> Result<TxValidationServer, Error> {
         let ctx = Context::new();
+        let frontend = ctx.socket(ROUTER)?;
+        frontend.bind(connection_str)?;
+        let mutating = ctx.socket(DEALER)?;
+        mutating.bind(MUTATING_ENDPOINT)?;
+        let readers = ctx.socket(DEALER)?;
+        readers.bind(READERS_ENDPOINT)?;
+        let checkers = ctx.socket(DEALER)?;
+        checkers.bind(CHECKERS_ENDPOINT)?;
+        let committing = Arc::new(RwLock::new(()));
+        let audit = StagedAudit::default();
+
+        for _ in 0..READER_WORKERS {
+            let socket = ctx.socket(REP)?;
+            socket.connect(READERS_ENDPOINT)?;
+            let worker = ReaderWorker {
+                txdb: txdb.clone(),
+                metadb: metadb.clone(),
+                filterdb: filterdb.clone(),
+            };
+            thread::spawn(move || worker.serve(socket));
+        }
+        for _ in 0..CHECK_WORKERS {
+            let socket = ctx.socket(REP)?;
+            socket.connect(CHECKERS_ENDPOINT)?;
+            let mut worker = CheckWorker {
+                eid: enclave.geteid(),
+                txdb: txdb.clone(),
+                metadb: metadb.clone(),
+                staged: StagedBlock::default(),
+                audit: audit.clone(),
+                committing: committing.clone(),
+            };
+            thread::spawn(move || worker.serve(socket));
+        }
         let socket = ctx.socket(REP)?;
-        socket.bind(connection_str)?;
-        Ok(TxValidationServer {
-            socket,
+        socket.connect(MUTATING_ENDPOINT)?;
+        let mut worker = MutatingWorker {
             enclave,
             txdb,
             metadb,
+            filterdb,
+            spentdb,
+            auditdb,
+            block_filter: None,
+            staged: StagedBlock::default(),
+            audit,
+            committing,
+        };
+        thread::spawn(move || worker.serve(socket));
+
+        Ok(TxValidationServer {
+            frontend,
+            mutating,
+            readers,
+            checkers,
         })
     }
 
-    fn lookup_txids<I>(&self, inputs: I) -> Option<Vec<Vec<u8>>>
-    where
-        I: IntoIterator<Item = TxId> + ExactSizeIterator,
-    {
-        let mut result = Vec::with_capacity(inputs.len());
-        for input in inputs.into_iter() {
-            if let Ok(Some(txin)) = self.txdb.get(input) {
-                result.push(txin.to_vec());
-            } else {
-                return None;
+    /// forwards a message between two sockets (including the envelope frames)
+    fn forward(from: &Socket, to: &Socket) {
+        match from.recv_multipart(FLAGS) {
+            Ok(parts) => {
+                if let Err(e) = to.send_multipart(parts, FLAGS) {
+                    warn!("failed to forward a message (dropped): {}", e);
+                }
             }
+            Err(e) => debug!("failed to receive a message: {}", e),
         }
-        Some(result)
     }
 
-    fn lookup(&self, tx: &TxAux) -> Option<Vec<Vec<u8>>> {
-        match tx {
-            TxAux::TransferTx { inputs, .. } => self.lookup_txids(inputs.iter().map(|x| x.id)),
-            TxAux::DepositStakeTx {
-                tx: DepositBondTx { inputs, .. },
-                ..
-            } => self.lookup_txids(inputs.iter().map(|x| x.id)),
-            _ => None,
+    /// forwards a client request to the worker(s) that should handle it
+    fn dispatch(&self) {
+        let parts = match self.frontend.recv_multipart(FLAGS) {
+            Ok(parts) => parts,
+            Err(e) => {
+                debug!("failed to receive a request: {}", e);
+                return;
+            }
+        };
+        let backend = match parts.last().map(|body| route(body)) {
+            Some(Route::Reader) => &self.readers,
+            Some(Route::Checker) => &self.checkers,
+            _ => &self.mutating,
+        };
+        if let Err(e) = backend.send_multipart(parts, FLAGS) {
+            warn!("failed to forward a request (dropped): {}", e);
         }
     }
 
     pub fn execute(&mut self) {
         info!("running zmq server");
         loop {
-            if let Ok(msg) = self.socket.recv_bytes(FLAGS) {
-                debug!("received a message");
-                let mcmd = EnclaveRequest::decode(&mut msg.as_slice());
-                let resp = match mcmd {
-                    Ok(EnclaveRequest::CheckChain {
-                        chain_hex_id,
-                        last_app_hash,
-                    }) => {
-                        debug!("check chain");
-                        match self.txdb.get(b"last_apphash") {
-                            Err(_) => EnclaveResponse::CheckChain(Err(None)),
-                            Ok(s) => {
-                                let ss = s.map(|stored| {
-                                    let mut app_hash = [0u8; 32];
-                                    app_hash.copy_from_slice(&stored);
-                                    app_hash
-                                });
-                                if last_app_hash == ss {
-                                    EnclaveResponse::CheckChain(check_initchain(
-                                        self.enclave.geteid(),
-                                        chain_hex_id,
-                                        ss,
-                                    ))
-                                } else {
-                                    EnclaveResponse::CheckChain(Err(ss))
+            let mut items = [
+                self.frontend.as_poll_item(POLLIN),
+                self.mutating.as_poll_item(POLLIN),
+                self.readers.as_poll_item(POLLIN),
+                self.checkers.as_poll_item(POLLIN),
+            ];
+            if zmq::poll(&mut items, -1).is_err() {
+                continue;
+            }
+            let (request, mutated, read, checked) = (
+                items[0].is_readable(),
+                items[1].is_readable(),
+                items[2].is_readable(),
+                items[3].is_readable(),
+            );
+            if request {
+                self.dispatch();
+            }
+            if mutated {
+                Self::forward(&self.mutating, &self.frontend);
+            }
+            if read {
+                Self::forward(&self.readers, &self.frontend);
+            }
+            if checked {
+                Self::forward(&self.checkers, &self.frontend);
+            }
+        }
+    }
+}
+
+/// the requests served by the enclave bridge (chain-abci's or the extensions)
+enum Request {
+    Enclave(EnclaveRequest),
+    Ext(ExtEnclaveRequest),
+}
+
+fn decode_request(msg: &[u8]) -> Option<Request> {
+    match EnclaveRequest::decode(&mut &msg[..]) {
+        Ok(request) => Some(Request::Enclave(request)),
+        Err(e) => match ExtEnclaveRequest::decode(&mut &msg[..]) {
+            Ok(request) => Some(Request::Ext(request)),
+            Err(_) => {
+                debug!("unknown request / failed to decode: {}", e);
+                None
+            }
+        },
+    }
+}
+
+/// the workers a request is dispatched to
+enum Route {
+    /// the request changes the state, so it's processed in order
+    Mutating,
+    /// the request only looks up the stored data (and can be served out of order)
+    Reader,
+    /// the request validates a transaction for the mempool (which doesn't change the current block)
+    Checker,
+}
+
+fn route(msg: &[u8]) -> Route {
+    match decode_request(msg) {
+        Some(Request::Enclave(EnclaveRequest::GetSealedTxData { .. }))
+        | Some(Request::Enclave(EnclaveRequest::GetCachedLaunchToken { .. }))
+        | Some(Request::Ext(ExtEnclaveRequest::GetBlockFilters { .. })) => Route::Reader,
+        Some(Request::Enclave(EnclaveRequest::VerifyTx(_)))
+        | Some(Request::Ext(ExtEnclaveRequest::CheckTx(_))) => Route::Checker,
+        _ => Route::Mutating,
+    }
+}
+
+/// the sealed transactions -- the staged ones (delivered in the current block) are only looked up if `staged` is passed
+fn lookup_txids<K: KeySpace, I>(
+    txdb: &K,
+    staged: Option<&StagedBlock>,
+    inputs: I,
+) -> Option<Vec<Vec<u8>>>
+where
+    I: IntoIterator<Item = TxId> + ExactSizeIterator,
+{
+    let mut result = Vec::with_capacity(inputs.len());
+    for input in inputs.into_iter() {
+        if let Some(txin) = staged.and_then(|staged| staged.get(&input)) {
+            result.push(txin.to_vec());
+        } else if let Ok(Some(txin)) = txdb.get(input) {
+            result.push(txin.to_vec());
+        } else {
+            return None;
+        }
+    }
+    Some(result)
+}
+
+fn lookup_inputs<K: KeySpace>(txdb: &K, staged: &StagedBlock, tx: &TxAux) -> Option<Vec<Vec<u8>>> {
+    match tx {
+        TxAux::TransferTx { inputs, .. } => {
+            lookup_txids(txdb, Some(staged), inputs.iter().map(|x| x.id))
+        }
+        TxAux::DepositStakeTx {
+            tx: DepositBondTx { inputs, .. },
+            ..
+        } => lookup_txids(txdb, Some(staged), inputs.iter().map(|x| x.id)),
+        _ => None,
+    }
+}
+
+/// validates the transaction (None if it's not a valid request)
+fn verify_tx<K: KeySpace>(
+    eid: sgx_enclave_id_t,
+    txdb: &K,
+    metadb: &mut K,
+    staged: &mut StagedBlock,
+    audit: &StagedAudit,
+    req: Box<VerifyTxRequest>,
+    mode: ValidationMode,
+) -> Option<Result<AttestedResult, TxRejection>> {
+    let chid = req.info.chain_hex_id;
+    let mtxins = lookup_inputs(txdb, staged, &req.tx);
+    if is_basic_valid_tx_request(&req, &mtxins, chid).is_err() {
+        None
+    } else {
+        Some(check_tx(
+            eid,
+            IntraEnclaveRequest::ValidateTx {
+                request: req,
+                tx_inputs: mtxins,
+            },
+            mode,
+            metadb,
+            staged,
+            audit,
+        ))
+    }
+}
+
+/// Serves the lookups that only read the storage (no enclave calls).
+struct ReaderWorker<K: KeySpace> {
+    txdb: K,
+    metadb: K,
+    filterdb: K,
+}
+
+impl<K: KeySpace> ReaderWorker<K> {
+    fn handle_request(&self, request: EnclaveRequest) -> Vec<u8> {
+        let response = match request {
+            EnclaveRequest::GetCachedLaunchToken { enclave_metaname } => {
+                EnclaveResponse::GetCachedLaunchToken(get_token_arr(
+                    &self.metadb,
+                    &enclave_metaname,
+                ))
+            }
+            EnclaveRequest::GetSealedTxData { txids } => EnclaveResponse::GetSealedTxData(
+                lookup_txids(&self.txdb, None, txids.iter().map(|x| *x)),
+            ),
+            _ => return EnclaveResponse::UnknownRequest.encode(),
+        };
+        response.encode()
+    }
+
+    fn handle_ext_request(&self, request: ExtEnclaveRequest) -> Vec<u8> {
+        match request {
+            ExtEnclaveRequest::GetBlockFilters { from, limit } => {
+                ExtEnclaveResponse::GetBlockFilters(get_block_filters(
+                    &self.filterdb,
+                    from,
+                    limit.min(MAX_BLOCK_FILTERS),
+                ))
+                .encode()
+            }
+            _ => EnclaveResponse::UnknownRequest.encode(),
+        }
+    }
+
+    fn serve(&self, socket: Socket) {
+        loop {
+            if let Ok(msg) = socket.recv_bytes(FLAGS) {
+                debug!("received a read-only request");
+                let response = match decode_request(&msg) {
+                    Some(Request::Enclave(request)) => self.handle_request(request),
+                    Some(Request::Ext(request)) => self.handle_ext_request(request),
+                    None => EnclaveResponse::UnknownRequest.encode(),
+                };
+                socket.send(response, FLAGS).expect("reply sending failed");
+            }
+        }
+    }
+}
+
+/// Validates transactions for the mempool (several of them run in parallel, in different enclave threads).
+struct CheckWorker<K: KeySpace> {
+    eid: sgx_enclave_id_t,
+    txdb: K,
+    metadb: K,
+    /// nothing is sealed in mempool checks, so it stays empty
+    staged: StagedBlock,
+    /// shared with the mutating worker (which stores the entries with the committed block)
+    audit: StagedAudit,
+    /// held (for reading) while a check is recorded in the audit log, so that a block isn't committed
+    /// before the audit log entry of a check that's already in the enclave's audit log head is staged
+    /// (the lock is fair, i.e. new checks wait behind a waiting commit, so a stream of checks can't hold it off)
+    committing: Arc<RwLock<()>>,
+}
+
+impl<K: KeySpace> CheckWorker<K> {
+    /// validates the transaction (None if it's not a valid request)
+    fn check_tx(
+        &mut self,
+        req: Box<VerifyTxRequest>,
+    ) -> Option<Result<AttestedResult, TxRejection>> {
+        let _checking = self.committing.read();
+        verify_tx(
+            self.eid,
+            &self.txdb,
+            &mut self.metadb,
+            &mut self.staged,
+            &self.audit,
+            req,
+            ValidationMode::Check,
+        )
+    }
+
+    fn serve(&mut self, socket: Socket) {
+        loop {
+            if let Ok(msg) = socket.recv_bytes(FLAGS) {
+                debug!("received a mempool check");
+                let response =
+                    match decode_request(&msg) {
+                        // the standard request is sent by Tendermint's CheckTx as well as DeliverTx,
+                        // so it's only validated (the blocks are delivered with `ExtEnclaveRequest::VerifyTx`);
+                        // the response has no room for the enclave's signature, and the enclave's rejection
+                        // is appended to it (see `verify_tx_rejection`)
+                        Some(Request::Enclave(EnclaveRequest::VerifyTx(req))) => {
+                            match self.check_tx(req) {
+                                Some(Err(TxRejection::Enclave(rejection))) => {
+                                    let mut response =
+                                        EnclaveResponse::VerifyTx(Err(TxError::EnclaveRejected))
+                                            .encode();
+                                    rejection.encode_to(&mut response);
+                                    response
                                 }
+                                Some(result) => EnclaveResponse::VerifyTx(
+                                    result
+                                        .map(|(fee, account, _)| (fee, account))
+                                        .map_err(TxError::from),
+                                )
+                                .encode(),
+                                None => EnclaveResponse::UnsupportedTxType.encode(),
                             }
                         }
-                    }
-                    Ok(EnclaveRequest::EndBlock) => EnclaveResponse::EndBlock(end_block(
-                        self.enclave.geteid(),
-                        IntraEnclaveRequest::EndBlock,
-                    )),
-                    Ok(EnclaveRequest::CommitBlock { app_hash }) => {
-                        let _ = self.txdb.insert(b"last_apphash", &app_hash);
-                        if let Ok(_) = self.txdb.flush() {
-                            EnclaveResponse::CommitBlock(Ok(()))
-                        } else {
-                            EnclaveResponse::CommitBlock(Err(()))
+                        Some(Request::Ext(ExtEnclaveRequest::CheckTx(req))) => {
+                            ExtEnclaveResponse::CheckTx(self.check_tx(Box::new(req)).unwrap_or(
+                                Err(TxRejection::Enclave(EnclaveRejection::InvalidRequest)),
+                            ))
+                            .encode()
                         }
+                        _ => EnclaveResponse::UnknownRequest.encode(),
+                    };
+                socket.send(response, FLAGS).expect("reply sending failed");
+            }
+        }
+    }
+}
+
+/// Handles the requests that use the enclave or change the stored state, one at a time.
+struct MutatingWorker<K: KeySpace> {
+    enclave: SgxEnclave,
+    txdb: K,
+    metadb: K,
+    filterdb: K,
+    spentdb: K,
+    auditdb: K,
+    /// the filter returned on EndBlock (stored when the block is committed)
+    block_filter: Option<Box<[u8; 256]>>,
+    /// the transactions delivered in the current block (stored when the block is committed)
+    staged: StagedBlock,
+    /// the audit log entries since the last committed block (stored when the block is committed)
+    audit: StagedAudit,
+    /// held (for writing) while a block is committed
+    committing: Arc<RwLock<()>>,
+}
+
+impl<K: KeySpace> MutatingWorker<K> {
+    /// the transaction ids of the inputs spent by the transaction
+    fn spent_txids(tx: &TxAux) -> Vec<TxId> {
+        match tx {
+            TxAux::TransferTx { inputs, .. } => inputs.iter().map(|x| x.id).collect(),
+            TxAux::DepositStakeTx {
+                tx: DepositBondTx { inputs, .. },
+                ..
+            } => inputs.iter().map(|x| x.id).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// validates the transaction (None if it's not a valid request)
+    fn verify_tx(
+        &mut self,
+        req: Box<VerifyTxRequest>,
+        mode: ValidationMode,
+    ) -> Option<Result<AttestedResult, TxRejection>> {
+        verify_tx(
+            self.enclave.geteid(),
+            &self.txdb,
+            &mut self.metadb,
+            &mut self.staged,
+            &self.audit,
+            req,
+            mode,
+        )
+    }
+
+    fn run_batch(
+        &mut self,
+        batch: Vec<(usize, IntraEnclaveRequest)>,
+        results: &mut [Option<Result<AttestedResult, TxRejection>>],
+    ) {
+        if batch.is_empty() {
+            return;
+        }
+        let (indices, requests): (Vec<usize>, Vec<IntraEnclaveRequest>) = batch.into_iter().unzip();
+        let batch_results = check_tx_batch(
+            self.enclave.geteid(),
+            requests,
+            &mut self.metadb,
+            &mut self.staged,
+            &self.audit,
+        );
+        for (i, result) in indices.into_iter().zip(batch_results.into_iter()) {
+            results[i] = Some(result);
+        }
+    }
+
+    /// validates the transactions in as few enclave calls as possible:
+    /// a new batch is started whenever a transaction spends outputs of one in the current batch
+    /// (whose sealed outputs need to be stored first)
+    fn verify_tx_batch(
+        &mut self,
+        requests: Vec<VerifyTxRequest>,
+    ) -> Vec<Result<AttestedResult, TxRejection>> {
+        let mut results: Vec<Option<Result<AttestedResult, TxRejection>>> =
+            requests.iter().map(|_| None).collect();
+        let mut batch: Vec<(usize, IntraEnclaveRequest)> = Vec::new();
+        let mut batch_txids: BTreeSet<TxId> = BTreeSet::new();
+        for (i, req) in requests.into_iter().enumerate() {
+            if Self::spent_txids(&req.tx)
+                .iter()
+                .any(|txid| batch_txids.contains(txid))
+            {
+                self.run_batch(batch.drain(..).collect(), &mut results);
+                batch_txids.clear();
+            }
+            let chid = req.info.chain_hex_id;
+            let mtxins = lookup_inputs(&self.txdb, &self.staged, &req.tx);
+            if is_basic_valid_tx_request(&req, &mtxins, chid).is_err() {
+                results[i] = Some(Err(TxRejection::Enclave(EnclaveRejection::InvalidRequest)));
+            } else {
+                batch_txids.insert(req.tx.tx_id());
+                batch.push((
+                    i,
+                    IntraEnclaveRequest::ValidateTx {
+                        request: Box::new(req),
+                        tx_inputs: mtxins,
+                    },
+                ));
+            }
+        }
+        self.run_batch(batch, &mut results);
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or(Err(TxRejection::Validation(TxError::EnclaveRejected))))
+            .collect()
+    }
+
+    /// returns the block filter (which is stored when the block is committed) and the unspent outputs commitment
+    fn end_block(&mut self) -> Result<(Box<[u8; 256]>, H256), ()> {
+        let result = end_block(self.enclave.geteid(), IntraEnclaveRequest::EndBlock);
+        match &result {
+            Ok((filter, utxo_root)) => {
+                debug!("unspent outputs commitment: {}", hex::encode(utxo_root));
+                self.block_filter = Some(filter.clone());
+            }
+            Err(_) => {
+                self.block_filter = None;
+            }
+        }
+        result
+    }
+
+    fn handle_request(&mut self, request: EnclaveRequest) -> EnclaveResponse {
+        match request {
+            EnclaveRequest::CheckChain {
+                chain_hex_id,
+                last_app_hash,
+            } => {
+                debug!("check chain");
+                if !self.staged.is_empty() {
+                    info!(
+                        "discarding {} transactions of an abandoned block",
+                        self.staged.len()
+                    );
+                    self.block_filter = None;
+                    if abandon_block(self.enclave.geteid(), &mut self.staged, &mut self.metadb)
+                        .is_err()
+                    {
+                        return EnclaveResponse::CheckChain(Err(None));
                     }
-                    Ok(EnclaveRequest::VerifyTx(req)) => {
-                        let chid = req.info.chain_hex_id;
-                        let mtxins = self.lookup(&req.tx);
-                        if is_basic_valid_tx_request(&req, &mtxins, chid).is_err() {
-                            EnclaveResponse::UnsupportedTxType
+                }
+                match self.metadb.get(LAST_APP_HASH_KEY) {
+                    Err(_) => EnclaveResponse::CheckChain(Err(None)),
+                    Ok(s) => {
+                        let ss = s.map(|stored| {
+                            let mut app_hash = [0u8; 32];
+                            app_hash.copy_from_slice(&stored);
+                            app_hash
+                        });
+                        if last_app_hash == ss {
+                            let eid = self.enclave.geteid();
+                            EnclaveResponse::CheckChain(
+                                check_initchain(eid, chain_hex_id, ss, &mut self.metadb).and_then(
+                                    |_| match check_checkpoint(eid, ss, &self.metadb) {
+                                        Ok(_) => restore_spent_set(eid, &self.spentdb)
+                                            .and_then(|_| {
+                                                restore_audit_log(eid, &self.auditdb, &self.audit)
+                                            })
+                                            .and_then(|_| restore_block_filter(eid, &self.metadb))
+                                            .map_err(|_| None),
+                                        Err(CheckpointError::AppHashMismatch) => Err(ss),
+                                        Err(_) => Err(None),
+                                    },
+                                ),
+                            )
                         } else {
-                            EnclaveResponse::VerifyTx(check_tx(
-                                self.enclave.geteid(),
-                                IntraEnclaveRequest::ValidateTx {
-                                    request: req,
-                                    tx_inputs: mtxins,
-                                },
-                                &mut self.txdb,
-                            ))
+                            EnclaveResponse::CheckChain(Err(ss))
                         }
                     }
-                    Ok(EnclaveRequest::GetCachedLaunchToken { enclave_metaname }) => {
-                        EnclaveResponse::GetCachedLaunchToken(get_token_arr(
-                            &self.metadb,
-                            &enclave_metaname,
-                        ))
-                    }
-                    Ok(EnclaveRequest::UpdateCachedLaunchToken {
-                        enclave_metaname,
-                        token,
-                    }) => EnclaveResponse::UpdateCachedLaunchToken(store_token(
-                        &mut self.metadb,
-                        &enclave_metaname,
-                        token.to_vec(),
-                    )),
-                    Ok(EnclaveRequest::GetSealedTxData { txids }) => {
-                        EnclaveResponse::GetSealedTxData(
-                            self.lookup_txids(txids.iter().map(|x| *x)),
-                        )
-                    }
-                    Err(e) => {
-                        debug!("unknown request / failed to decode: {}", e);
-                        EnclaveResponse::UnknownRequest
-                    }
+                }
+            }
+            EnclaveRequest::EndBlock => {
+                EnclaveResponse::EndBlock(self.end_block().map(|(filter, _)| filter))
+            }
+            EnclaveRequest::CommitBlock { app_hash } => {
+                let _committing = self.committing.write();
+                let block_filter = self.block_filter.take();
+                let result = commit_block(
+                    self.enclave.geteid(),
+                    &app_hash,
+                    block_filter.as_ref().map(|filter| &**filter),
+                    &mut self.staged,
+                    &self.audit,
+                    &mut self.txdb,
+                    &mut self.metadb,
+                    &mut self.filterdb,
+                    &mut self.spentdb,
+                    &self.auditdb,
+                );
+                if let Ok(height) = result {
+                    debug!("committed block {}", height);
+                }
+                EnclaveResponse::CommitBlock(result.map(|_| ()))
+            }
+            EnclaveRequest::UpdateCachedLaunchToken {
+                enclave_metaname,
+                token,
+            } => EnclaveResponse::UpdateCachedLaunchToken(store_token(
+                &mut self.metadb,
+                &enclave_metaname,
+                token.to_vec(),
+            )),
+            // the read-only requests are routed to the readers (and the transaction validation to the checkers)
+            _ => EnclaveResponse::UnknownRequest,
+        }
+    }
+
+    fn handle_ext_request(&mut self, request: ExtEnclaveRequest) -> ExtEnclaveResponse {
+        match request {
+            ExtEnclaveRequest::GetEncryptionParams => ExtEnclaveResponse::GetEncryptionParams(
+                get_encryption_params(self.enclave.geteid()),
+            ),
+            ExtEnclaveRequest::RotateEncryptionKey { key_from } => {
+                info!("rotating the obfuscation key (from {})", key_from);
+                ExtEnclaveResponse::RotateEncryptionKey(rotate_obfuscation_key(
+                    self.enclave.geteid(),
+                    key_from,
+                    &mut self.metadb,
+                ))
+            }
+            ExtEnclaveRequest::InitChain(genesis) => {
+                info!("init chain (network id: {:x})", genesis.chain_hex_id);
+                ExtEnclaveResponse::InitChain(init_chain(
+                    self.enclave.geteid(),
+                    genesis.chain_hex_id,
+                    Some(&genesis),
+                    &mut self.metadb,
+                ))
+            }
+            ExtEnclaveRequest::VerifyTxBatch(requests) => {
+                debug!("verify {} transactions", requests.len());
+                ExtEnclaveResponse::VerifyTxBatch(self.verify_tx_batch(requests))
+            }
+            ExtEnclaveRequest::CheckTx(req) => ExtEnclaveResponse::CheckTx(
+                self.verify_tx(Box::new(req), ValidationMode::Check)
+                    .unwrap_or(Err(TxRejection::Enclave(EnclaveRejection::InvalidRequest))),
+            ),
+            ExtEnclaveRequest::GetBlockFilters { from, limit } => {
+                ExtEnclaveResponse::GetBlockFilters(get_block_filters(
+                    &self.filterdb,
+                    from,
+                    limit.min(MAX_BLOCK_FILTERS),
+                ))
+            }
+            ExtEnclaveRequest::EndBlock => ExtEnclaveResponse::EndBlock(self.end_block()),
+            ExtEnclaveRequest::VerifyAuditLog => {
+                info!("verifying the audit log");
+                ExtEnclaveResponse::VerifyAuditLog(verify_audit_log(
+                    self.enclave.geteid(),
+                    &self.auditdb,
+                    &self.audit,
+                ))
+            }
+            ExtEnclaveRequest::GetValidationKey { target_info } => {
+                ExtEnclaveResponse::GetValidationKey(get_validation_key(
+                    self.enclave.geteid(),
+                    &target_info,
+                ))
+            }
+            ExtEnclaveRequest::VerifyTx(req) => ExtEnclaveResponse::VerifyTx(
+                self.verify_tx(Box::new(req), ValidationMode::Deliver)
+                    .unwrap_or(Err(TxRejection::Enclave(EnclaveRejection::InvalidRequest))),
+            ),
+        }
+    }
+
+    fn serve(&mut self, socket: Socket) {
+        loop {
+            if let Ok(msg) = socket.recv_bytes(FLAGS) {
+                debug!("received a message");
+                let response = match decode_request(&msg) {
+                    Some(Request::Enclave(request)) => self.handle_request(request).encode(),
+                    Some(Request::Ext(request)) => self.handle_ext_request(request).encode(),
+                    None => EnclaveResponse::UnknownRequest.encode(),
                 };
-                let response = resp.encode();
-                self.socket
-                    .send(response, FLAGS)
-                    .expect("reply sending failed");
+                socket.send(response, FLAGS).expect("reply sending failed");
             }
         }
     }
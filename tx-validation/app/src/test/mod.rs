@@ -1,12 +1,21 @@
-use crate::enclave_u::{check_initchain, check_tx, end_block};
-use crate::enclave_u::{get_token, store_token};
+use crate::enclave_u::{
+    abandon_block, check_checkpoint, check_initchain, check_tx, check_tx_batch, commit_block,
+    end_block, get_block_filters, get_encryption_params, get_validation_key, init_chain,
+    init_obfuscation_keys, init_sealing_policy, init_signing_key, restore_audit_log,
+    restore_block_filter, restore_spent_set, verify_audit_log, CheckpointError, StagedAudit,
+    StagedBlock, BLOCK_FILTER_KEY, CHECKPOINT_KEY, GENESIS_KEY, LAST_APP_HASH_KEY,
+    SEALING_MIGRATION_KEY,
+};
+use crate::migrations::{migrate, stored_version, SCHEMA_VERSION};
+use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead, Payload};
+use aes_gcm::Aes128Gcm;
 use chain_core::common::MerkleTree;
 use chain_core::init::address::RedeemAddress;
 use chain_core::init::coin::Coin;
 use chain_core::state::account::{
     StakedState, StakedStateAddress, StakedStateOpWitness, WithdrawUnbondedTx,
 };
-use chain_core::tx::fee::Fee;
+use chain_core::tx::fee::{Fee, LinearFee, Milli};
 use chain_core::tx::witness::tree::RawPubkey;
 use chain_core::tx::witness::EcdsaSignature;
 use chain_core::tx::PlainTxAux;
@@ -27,15 +36,25 @@ use chain_core::tx::{
 use chain_core::ChainInfo;
 use chain_tx_validation::Error;
 use enclave_protocol::{IntraEnclaveRequest, VerifyTxRequest};
+use enclave_protocol_ext::{
+    ChainGenesis, ChainParams, EnclaveRejection, EncryptionParams, SealingPolicy, TxRejection,
+    ValidationMode, ValidationStatement, KEYPOLICY_MRENCLAVE,
+};
 use enclave_u_common::enclave_u::{init_enclave, VALIDATION_TOKEN_KEY};
+use enclave_u_common::storage::memory::MemoryStorage;
+use enclave_u_common::storage::{get_token, store_token, KeySpace, Storage};
+use enclave_u_common::SCHEMA_VERSION_KEY;
 use env_logger::{Builder, WriteStyle};
 use log::LevelFilter;
 use log::{debug, error, info};
 use parity_scale_codec::Encode;
 use secp256k1::{
-    key::PublicKey, key::SecretKey, schnorrsig::schnorr_sign, Message, Secp256k1, Signing,
+    ecdh::SharedSecret, key::PublicKey, key::SecretKey, schnorrsig::schnorr_sign, Message,
+    Secp256k1, Signature, Signing,
 };
-use sled::Db;
+use sgx_types::{sgx_report_t, sgx_target_info_t};
+use std::mem::size_of;
+use std::thread;
 
 pub fn get_ecdsa_witness<C: Signing>(
     secp: &Secp256k1<C>,
@@ -58,9 +77,86 @@ fn get_account(account_address: &RedeemAddress) -> StakedState {
 
 const TEST_NETWORK_ID: u8 = 0xab;
 
-fn cleanup(db: &mut Db) {
-    db.drop_tree(crate::META_KEYSPACE).expect("test meta tx");
-    db.drop_tree(crate::TX_KEYSPACE).expect("test cleanup tx");
+/// encrypts the payload to the enclave's current obfuscation key
+/// (the ephemeral key is derived from the transaction id to keep the test deterministic)
+fn encrypt(
+    params: &EncryptionParams,
+    txid: &TxId,
+    plain: &PlainTxAux,
+    nonce: [u8; 12],
+) -> TxObfuscated {
+    let secp = Secp256k1::new();
+    let ephemeral_key = SecretKey::from_slice(&txid[..]).expect("32 bytes, within curve order");
+    let enclave_key = PublicKey::from_slice(params.public_key.as_bytes()).expect("public key");
+    let shared_secret = SharedSecret::new(&enclave_key, &ephemeral_key);
+    let cipher = Aes128Gcm::new(GenericArray::clone_from_slice(&shared_secret[..16]));
+    let encrypted = cipher
+        .encrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: &plain.encode(),
+                aad: &txid[..],
+            },
+        )
+        .expect("encryption");
+    let mut txpayload = PublicKey::from_secret_key(&secp, &ephemeral_key)
+        .serialize()
+        .to_vec();
+    txpayload.extend(encrypted);
+    TxObfuscated {
+        key_from: params.key_from,
+        nonce,
+        txpayload,
+    }
+}
+
+fn cleanup(storage: &MemoryStorage) {
+    storage
+        .drop_keyspace(crate::META_KEYSPACE)
+        .expect("test meta tx");
+    storage
+        .drop_keyspace(crate::TX_KEYSPACE)
+        .expect("test cleanup tx");
+    storage
+        .drop_keyspace(crate::FILTER_KEYSPACE)
+        .expect("test cleanup filter");
+    storage
+        .drop_keyspace(crate::SPENT_KEYSPACE)
+        .expect("test cleanup spent");
+    storage
+        .drop_keyspace(crate::AUDIT_KEYSPACE)
+        .expect("test cleanup audit");
+}
+
+/// signs the transfer with the test key and encrypts it to the enclave
+fn transfer_tx(
+    params: &EncryptionParams,
+    tx: &Tx,
+    secret_key: &SecretKey,
+    merkle_tree: &MerkleTree<RawPubkey>,
+    nonce: [u8; 12],
+) -> TxAux {
+    let secp = Secp256k1::new();
+    let public_key = PublicKey::from_secret_key(&secp, secret_key);
+    let txid = tx.id();
+    let witness = vec![TxInWitness::TreeSig(
+        schnorr_sign(&secp, &Message::from_slice(&txid).unwrap(), secret_key).0,
+        merkle_tree
+            .generate_proof(RawPubkey::from(public_key.serialize()))
+            .unwrap(),
+    )]
+    .into();
+    TxAux::TransferTx {
+        txid,
+        inputs: tx.inputs.clone(),
+        no_of_outputs: tx.outputs.len() as TxoIndex,
+        payload: encrypt(
+            params,
+            &txid,
+            &PlainTxAux::TransferTx(tx.clone(), witness),
+            nonce,
+        ),
+    }
 }
 
 /// Unfortunately the usual Rust unit-test facility can't be used with Baidu SGX SDK,
@@ -72,15 +168,27 @@ pub fn test_sealing() {
         .filter(None, LevelFilter::Debug)
         .write_style(WriteStyle::Always)
         .init();
-    let mut db = Db::open(".enclave-test").expect("failed to open a storage path");
-    let mut metadb = db
-        .open_tree(crate::META_KEYSPACE)
+    let storage = MemoryStorage::default();
+    assert!(migrate(&storage).is_ok());
+    let mut metadb = storage
+        .open_keyspace(crate::META_KEYSPACE)
         .expect("failed to open a meta keyspace");
-    let mut txdb = db
-        .open_tree(crate::TX_KEYSPACE)
+    let mut txdb = storage
+        .open_keyspace(crate::TX_KEYSPACE)
         .expect("failed to open a tx keyspace");
+    let mut filterdb = storage
+        .open_keyspace(crate::FILTER_KEYSPACE)
+        .expect("failed to open a filter keyspace");
+    let mut spentdb = storage
+        .open_keyspace(crate::SPENT_KEYSPACE)
+        .expect("failed to open a spent keyspace");
+    let mut auditdb = storage
+        .open_keyspace(crate::AUDIT_KEYSPACE)
+        .expect("failed to open an audit keyspace");
+    let mut staged = StagedBlock::default();
+    let audit = StagedAudit::default();
 
-    let token = get_token(&metadb, VALIDATION_TOKEN_KEY);
+    let token = get_token(&metadb, VALIDATION_TOKEN_KEY).expect("launch token");
     let enclave = match init_enclave(true, token) {
         (Ok(r), new_token) => {
             info!("[+] Init Enclave Successful {}!", r.geteid());
@@ -94,19 +202,59 @@ pub fn test_sealing() {
             return;
         }
     };
-    assert!(check_initchain(enclave.geteid(), TEST_NETWORK_ID, None).is_ok());
-
-    let end_b = end_block(enclave.geteid(), IntraEnclaveRequest::EndBlock);
-    match end_b {
-        Ok(b) => {
-            debug!("request filter in the beginning");
-            assert!(b.iter().all(|x| *x == 0u8), "empty filter");
-        }
-        _ => {
-            cleanup(&mut db);
-            assert!(false, "filter not returned");
-        }
+    assert!(init_sealing_policy(
+        enclave.geteid(),
+        SealingPolicy::default(),
+        &mut txdb,
+        &mut metadb,
+        &mut spentdb,
+        &mut auditdb
+    )
+    .is_ok());
+    assert!(check_initchain(enclave.geteid(), TEST_NETWORK_ID, None, &mut metadb).is_ok());
+    let genesis = ChainGenesis {
+        chain_hex_id: TEST_NETWORK_ID,
+        genesis_app_hash: [0u8; 32],
+        params: ChainParams {
+            unbonding_period: 0,
+            fee_policy: LinearFee::new(Milli::new(0, 0), Milli::new(0, 0)),
+        },
     };
+    assert!(init_chain(
+        enclave.geteid(),
+        TEST_NETWORK_ID,
+        Some(&genesis),
+        &mut metadb
+    )
+    .is_ok());
+    assert!(init_chain(
+        enclave.geteid(),
+        TEST_NETWORK_ID,
+        Some(&genesis),
+        &mut metadb
+    )
+    .is_ok());
+    let other_genesis = ChainGenesis {
+        genesis_app_hash: [1u8; 32],
+        ..genesis.clone()
+    };
+    assert!(
+        init_chain(
+            enclave.geteid(),
+            TEST_NETWORK_ID,
+            Some(&other_genesis),
+            &mut metadb
+        )
+        .is_err(),
+        "different genesis accepted"
+    );
+    assert!(
+        check_initchain(enclave.geteid(), TEST_NETWORK_ID + 1, None, &mut metadb).is_err(),
+        "different network id accepted"
+    );
+    assert!(init_obfuscation_keys(enclave.geteid(), &mut metadb).is_ok());
+    assert!(init_signing_key(enclave.geteid(), &mut metadb).is_ok());
+    let params = get_encryption_params(enclave.geteid()).expect("encryption parameters");
 
     let secp = Secp256k1::new();
     let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
@@ -130,11 +278,12 @@ pub fn test_sealing() {
         txid: tx0.id(),
         no_of_outputs: tx0.outputs.len() as TxoIndex,
         witness: witness0,
-        payload: TxObfuscated {
-            key_from: 0,
-            nonce: [0u8; 12],
-            txpayload: PlainTxAux::WithdrawUnbondedStakeTx(tx0).encode(),
-        },
+        payload: encrypt(
+            &params,
+            &txid,
+            &PlainTxAux::WithdrawUnbondedStakeTx(tx0),
+            [0u8; 12],
+        ),
     };
     let account = get_account(&addr);
     let info = ChainInfo {
@@ -143,16 +292,200 @@ pub fn test_sealing() {
         previous_block_time: 1,
         unbonding_period: 0,
     };
+    let check_request = IntraEnclaveRequest::ValidateTx {
+        request: Box::new(VerifyTxRequest {
+            tx: withdrawtx.clone(),
+            account: Some(account.clone()),
+            info,
+        }),
+        tx_inputs: None,
+    };
+    match check_tx(
+        enclave.geteid(),
+        IntraEnclaveRequest::ValidateTx {
+            request: Box::new(VerifyTxRequest {
+                tx: withdrawtx.clone(),
+                account: Some(account.clone()),
+                info,
+            }),
+            tx_inputs: None,
+        },
+        ValidationMode::Check,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    ) {
+        Err(TxRejection::Enclave(EnclaveRejection::NotInitialized)) => {
+            debug!("tx checked before the chain state was restored rejected");
+        }
+        x => {
+            cleanup(&storage);
+            panic!(
+                "tx checked before CheckChain not rejected as NotInitialized: {:?}",
+                x
+            );
+        }
+    };
+    assert!(check_checkpoint(enclave.geteid(), None, &metadb).is_ok());
+    assert!(restore_spent_set(enclave.geteid(), &spentdb).is_ok());
+    assert!(restore_audit_log(enclave.geteid(), &auditdb, &audit).is_ok());
+
+    let end_b = end_block(enclave.geteid(), IntraEnclaveRequest::EndBlock);
+    match end_b {
+        Ok((b, utxo_root)) => {
+            debug!("request filter in the beginning");
+            assert!(b.iter().all(|x| *x == 0u8), "empty filter");
+            assert_eq!(utxo_root, [0u8; 32], "empty unspent outputs commitment");
+        }
+        _ => {
+            cleanup(&storage);
+            assert!(false, "filter not returned");
+        }
+    };
+
     let tb = txdb.get(&txid);
     match tb {
         Ok(None) => {
             debug!("new tx not in DB yet");
         }
         _ => {
-            cleanup(&mut db);
+            cleanup(&storage);
             assert!(false, "new tx already in db");
         }
     };
+    let rc = check_tx(
+        enclave.geteid(),
+        check_request,
+        ValidationMode::Check,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    );
+    let mut withdrawn = account.clone();
+    withdrawn.withdraw();
+    let (paid_fee, signature) = match rc {
+        Ok((paid_fee, Some(ref result), signature)) if *result == withdrawn => {
+            debug!("withdrawn staked state computed by the enclave");
+            (paid_fee, signature)
+        }
+        x => {
+            cleanup(&storage);
+            panic!("unexpected withdrawal result: {:?}", x);
+        }
+    };
+    let target_info = vec![0u8; size_of::<sgx_target_info_t>()];
+    let (validation_key, raw_report) =
+        get_validation_key(enclave.geteid(), &target_info).expect("validation key");
+    assert_eq!(raw_report.len(), size_of::<sgx_report_t>());
+    let report: sgx_report_t =
+        unsafe { std::ptr::read_unaligned(raw_report.as_ptr() as *const sgx_report_t) };
+    let mut reported_key = [0u8; 33];
+    reported_key.copy_from_slice(&report.body.report_data.d[..33]);
+    assert!(
+        RawPubkey::from(reported_key) == validation_key,
+        "validation key not in the report data"
+    );
+    // the sealed key is restored (as after a restart)
+    assert!(init_signing_key(enclave.geteid(), &mut metadb).is_ok());
+    let (restored_key, _) =
+        get_validation_key(enclave.geteid(), &target_info).expect("validation key");
+    assert!(
+        restored_key == validation_key,
+        "validation signing key not restored"
+    );
+    let statement = ValidationStatement {
+        txid: *txid,
+        paid_fee,
+        account: Some(withdrawn),
+    };
+    let mut compact_signature = signature.r.to_vec();
+    compact_signature.extend_from_slice(&signature.s);
+    assert!(
+        secp.verify(
+            &Message::from_slice(&statement.hash()).expect("32 bytes"),
+            &Signature::from_compact(&compact_signature).expect("compact signature"),
+            &PublicKey::from_slice(&reported_key).expect("public key"),
+        )
+        .is_ok(),
+        "invalid validation signature"
+    );
+    let wrong_fee_request = IntraEnclaveRequest::ValidateTx {
+        request: Box::new(VerifyTxRequest {
+            tx: withdrawtx.clone(),
+            account: Some(account.clone()),
+            info: ChainInfo {
+                min_fee_computed: Fee::new(Coin::one()),
+                ..info
+            },
+        }),
+        tx_inputs: None,
+    };
+    match check_tx(
+        enclave.geteid(),
+        wrong_fee_request,
+        ValidationMode::Check,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    ) {
+        Err(TxRejection::Enclave(EnclaveRejection::WrongFee)) => {
+            debug!("fee not matching the fee policy rejected");
+        }
+        x => {
+            cleanup(&storage);
+            panic!("fee not matching the fee policy accepted: {:?}", x);
+        }
+    };
+    let concurrent_checks: Vec<_> = (0..4)
+        .map(|_| {
+            let eid = enclave.geteid();
+            let request = IntraEnclaveRequest::ValidateTx {
+                request: Box::new(VerifyTxRequest {
+                    tx: withdrawtx.clone(),
+                    account: Some(account.clone()),
+                    info,
+                }),
+                tx_inputs: None,
+            };
+            let (mut metadb, audit) = (metadb.clone(), audit.clone());
+            thread::spawn(move || {
+                check_tx(
+                    eid,
+                    request,
+                    ValidationMode::Check,
+                    &mut metadb,
+                    &mut StagedBlock::default(),
+                    &audit,
+                )
+            })
+        })
+        .collect();
+    for check in concurrent_checks {
+        match check.join() {
+            Ok(Ok((fee, _, _))) if fee == paid_fee => {}
+            x => {
+                cleanup(&storage);
+                panic!("concurrent mempool check failed: {:?}", x);
+            }
+        }
+    }
+    assert_eq!(
+        verify_audit_log(enclave.geteid(), &auditdb, &audit),
+        Ok((auditdb.len().expect("audit entries") + audit.len()) as u64),
+        "concurrent mempool checks not chained in the audit log"
+    );
+    match (
+        txdb.get(&txid),
+        end_block(enclave.geteid(), IntraEnclaveRequest::EndBlock),
+    ) {
+        (Ok(None), Ok((b, utxo_root))) if b.iter().all(|x| *x == 0u8) && utxo_root == [0u8; 32] => {
+            debug!("checked tx not stored and filter not updated");
+        }
+        _ => {
+            cleanup(&storage);
+            panic!("mempool check modified the storage or the filter");
+        }
+    };
     let mut request0 = IntraEnclaveRequest::ValidateTx {
         request: Box::new(VerifyTxRequest {
             tx: withdrawtx,
@@ -161,32 +494,58 @@ pub fn test_sealing() {
         }),
         tx_inputs: None,
     };
-    let r = check_tx(enclave.geteid(), request0, &mut txdb);
+    let r = check_tx(
+        enclave.geteid(),
+        request0,
+        ValidationMode::Deliver,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    );
     assert!(r.is_ok());
-    let ta = txdb.get(&txid);
-    let sealedtx = match ta {
-        Ok(Some(tx)) => {
-            debug!("new tx in DB!");
+    assert!(
+        txdb.get(&txid).map(|x| x.is_none()).unwrap_or(false),
+        "delivered tx stored before the block is committed"
+    );
+    let sealedtx = match staged.get(&txid) {
+        Some(tx) => {
+            debug!("new tx staged in the block");
             tx.to_vec()
         }
-        _ => {
-            cleanup(&mut db);
-            assert!(false, "new tx not in db");
+        None => {
+            cleanup(&storage);
+            assert!(false, "new tx not staged");
             vec![]
         }
     };
 
     let end_b = end_block(enclave.geteid(), IntraEnclaveRequest::EndBlock);
     match end_b {
-        Ok(b) => {
+        Ok((b, utxo_root)) => {
             debug!("request filter after one tx");
             assert!(b.iter().any(|x| *x != 0u8), "non-empty filter");
+            assert!(utxo_root != [0u8; 32], "withdrawn output not committed");
         }
         _ => {
-            cleanup(&mut db);
+            cleanup(&storage);
             assert!(false, "filter not returned");
         }
     };
+    // the filter sealed after the delivered tx is restored (as after a restart in the middle of the block)
+    assert!(metadb
+        .get(BLOCK_FILTER_KEY)
+        .map(|x| x.is_some())
+        .unwrap_or(false));
+    assert!(restore_block_filter(enclave.geteid(), &metadb).is_ok());
+    match end_block(enclave.geteid(), IntraEnclaveRequest::EndBlock) {
+        Ok((b, _)) if b.iter().any(|x| *x != 0u8) => {
+            debug!("filter restored");
+        }
+        _ => {
+            cleanup(&storage);
+            panic!("filter not restored");
+        }
+    };
 
     let halfcoin = Coin::from(5000_0000u32);
     let utxo1 = TxoPointer::new(*txid, 0);
@@ -207,11 +566,7 @@ pub fn test_sealing() {
         txid: tx1.id(),
         inputs: tx1.inputs.clone(),
         no_of_outputs: tx1.outputs.len() as TxoIndex,
-        payload: TxObfuscated {
-            key_from: 0,
-            nonce: [0u8; 12],
-            txpayload: plain_txaux.encode(),
-        },
+        payload: encrypt(&params, &txid1, &plain_txaux, [1u8; 12]),
     };
 
     let tc = txdb.get(&txid1);
@@ -224,6 +579,31 @@ pub fn test_sealing() {
         }
     };
 
+    // Tendermint checks the transaction for the mempool before it's delivered in a block
+    match check_tx(
+        enclave.geteid(),
+        IntraEnclaveRequest::ValidateTx {
+            request: Box::new(VerifyTxRequest {
+                tx: transfertx.clone(),
+                account: None,
+                info,
+            }),
+            tx_inputs: Some(vec![sealedtx.clone()]),
+        },
+        ValidationMode::Check,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    ) {
+        Ok(_) if staged.get(&txid1).is_none() => {
+            debug!("new 2nd tx checked without staging it");
+        }
+        x => {
+            cleanup(&storage);
+            panic!("new 2nd tx check failed or staged it: {:?}", x);
+        }
+    };
+
     let mut request1 = IntraEnclaveRequest::ValidateTx {
         request: Box::new(VerifyTxRequest {
             tx: transfertx,
@@ -233,22 +613,54 @@ pub fn test_sealing() {
         tx_inputs: Some(vec![sealedtx.clone()]),
     };
 
-    let r2 = check_tx(enclave.geteid(), request1, &mut txdb);
+    let r2 = check_tx(
+        enclave.geteid(),
+        request1,
+        ValidationMode::Deliver,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    );
     assert!(r2.is_ok());
-    let td = txdb.get(&txid1);
-    match td {
-        Ok(Some(tx)) => {
-            debug!("new 2nd tx in DB!");
+    let sealedtx1 = match staged.get(&txid1) {
+        Some(tx) => {
+            debug!("new 2nd tx staged in the block");
+            tx.to_vec()
         }
-        _ => {
-            cleanup(&mut db);
-            assert!(false, "new 2nd tx not in db");
+        None => {
+            cleanup(&storage);
+            panic!("new 2nd tx not staged");
+        }
+    };
+
+    let double_spend = IntraEnclaveRequest::ValidateTx {
+        request: Box::new(VerifyTxRequest {
+            tx: transfer_tx(&params, &tx1, &secret_key, &merkle_tree, [5u8; 12]),
+            account: None,
+            info,
+        }),
+        tx_inputs: Some(vec![sealedtx.clone()]),
+    };
+    match check_tx(
+        enclave.geteid(),
+        double_spend,
+        ValidationMode::Deliver,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    ) {
+        Err(TxRejection::Enclave(EnclaveRejection::InputSpent)) => {
+            debug!("double spend rejected");
+        }
+        x => {
+            cleanup(&storage);
+            panic!("double spend not rejected: {:?}", x);
         }
     };
 
     let mut tx2 = Tx::new();
     tx2.attributes = TxAttributes::new(TEST_NETWORK_ID);
-    tx2.add_input(utxo1);
+    tx2.add_input(TxoPointer::new(txid1, 0));
     tx2.add_output(TxOut::new(eaddr.clone(), Coin::zero()));
     let txid2 = tx2.id();
     let witness2 = vec![TxInWitness::TreeSig(
@@ -263,11 +675,7 @@ pub fn test_sealing() {
         txid: tx2.id(),
         inputs: tx2.inputs.clone(),
         no_of_outputs: tx2.outputs.len() as TxoIndex,
-        payload: TxObfuscated {
-            key_from: 0,
-            nonce: [0u8; 12],
-            txpayload: plain_txaux2.encode(),
-        },
+        payload: encrypt(&params, &txid2, &plain_txaux2, [2u8; 12]),
     };
     let mut request2 = IntraEnclaveRequest::ValidateTx {
         request: Box::new(VerifyTxRequest {
@@ -275,16 +683,23 @@ pub fn test_sealing() {
             account: None,
             info,
         }),
-        tx_inputs: Some(vec![sealedtx]),
+        tx_inputs: Some(vec![sealedtx1.clone()]),
     };
 
-    let r3 = check_tx(enclave.geteid(), request2, &mut txdb);
+    let r3 = check_tx(
+        enclave.geteid(),
+        request2,
+        ValidationMode::Deliver,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    );
     match r3 {
-        Err(Error::ZeroCoin) => {
+        Err(TxRejection::Validation(Error::ZeroCoin)) => {
             debug!("invalid transaction rejected and error code returned");
         }
         x => {
-            cleanup(&mut db);
+            cleanup(&storage);
             panic!(
                 "something else happened (tx not correctly rejected): {:?}",
                 x
@@ -292,5 +707,475 @@ pub fn test_sealing() {
         }
     };
 
-    cleanup(&mut db);
+    let reused_nonce = IntraEnclaveRequest::ValidateTx {
+        request: Box::new(VerifyTxRequest {
+            tx: TxAux::TransferTx {
+                txid: tx2.id(),
+                inputs: tx2.inputs.clone(),
+                no_of_outputs: tx2.outputs.len() as TxoIndex,
+                payload: encrypt(&params, &txid2, &plain_txaux2, [1u8; 12]),
+            },
+            account: None,
+            info,
+        }),
+        tx_inputs: Some(vec![sealedtx1.clone()]),
+    };
+    match check_tx(
+        enclave.geteid(),
+        reused_nonce,
+        ValidationMode::Check,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    ) {
+        Err(TxRejection::Enclave(EnclaveRejection::NonceReuse)) => {
+            debug!("payload nonce used in the current block rejected");
+        }
+        x => {
+            cleanup(&storage);
+            panic!(
+                "payload nonce used in the current block not rejected: {:?}",
+                x
+            );
+        }
+    };
+
+    let mut tampered_payload = encrypt(&params, &txid2, &plain_txaux2, [3u8; 12]);
+    let last = tampered_payload.txpayload.len() - 1;
+    tampered_payload.txpayload[last] ^= 1;
+    let tampered_tx = TxAux::TransferTx {
+        txid: tx2.id(),
+        inputs: tx2.inputs.clone(),
+        no_of_outputs: tx2.outputs.len() as TxoIndex,
+        payload: tampered_payload,
+    };
+    let request3 = IntraEnclaveRequest::ValidateTx {
+        request: Box::new(VerifyTxRequest {
+            tx: tampered_tx,
+            account: None,
+            info,
+        }),
+        tx_inputs: Some(vec![sealedtx1.clone()]),
+    };
+    let r4 = check_tx(
+        enclave.geteid(),
+        request3,
+        ValidationMode::Deliver,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    );
+    match r4 {
+        Err(TxRejection::Enclave(EnclaveRejection::PayloadDecryption)) => {
+            debug!("tampered transaction payload rejected");
+        }
+        x => {
+            cleanup(&storage);
+            panic!("tampered payload not rejected: {:?}", x);
+        }
+    };
+
+    let swapped_input = sealedtx;
+    let swapped_tx = TxAux::TransferTx {
+        txid: tx2.id(),
+        inputs: tx2.inputs.clone(),
+        no_of_outputs: tx2.outputs.len() as TxoIndex,
+        payload: encrypt(&params, &txid2, &plain_txaux2, [4u8; 12]),
+    };
+    let request4 = IntraEnclaveRequest::ValidateTx {
+        request: Box::new(VerifyTxRequest {
+            tx: swapped_tx,
+            account: None,
+            info,
+        }),
+        tx_inputs: Some(vec![swapped_input]),
+    };
+    let r5 = check_tx(
+        enclave.geteid(),
+        request4,
+        ValidationMode::Deliver,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    );
+    match r5 {
+        Err(TxRejection::Enclave(EnclaveRejection::InputUnseal)) => {
+            debug!("sealed input of a different transaction rejected");
+        }
+        x => {
+            cleanup(&storage);
+            panic!("swapped sealed input not rejected: {:?}", x);
+        }
+    };
+
+    let mut tx3 = Tx::new();
+    tx3.attributes = TxAttributes::new(TEST_NETWORK_ID);
+    tx3.add_input(TxoPointer::new(txid1, 0));
+    tx3.add_output(TxOut::new(eaddr.clone(), Coin::from(2500_0000u32)));
+    let txid3 = tx3.id();
+    let batch = vec![
+        IntraEnclaveRequest::ValidateTx {
+            request: Box::new(VerifyTxRequest {
+                tx: TxAux::TransferTx {
+                    txid: tx2.id(),
+                    inputs: tx2.inputs.clone(),
+                    no_of_outputs: tx2.outputs.len() as TxoIndex,
+                    payload: encrypt(&params, &txid2, &plain_txaux2, [6u8; 12]),
+                },
+                account: None,
+                info,
+            }),
+            tx_inputs: Some(vec![sealedtx1.clone()]),
+        },
+        IntraEnclaveRequest::ValidateTx {
+            request: Box::new(VerifyTxRequest {
+                tx: transfer_tx(&params, &tx3, &secret_key, &merkle_tree, [7u8; 12]),
+                account: None,
+                info,
+            }),
+            tx_inputs: Some(vec![sealedtx1]),
+        },
+    ];
+    let batch_results = check_tx_batch(enclave.geteid(), batch, &mut metadb, &mut staged, &audit);
+    match batch_results.as_slice() {
+        [Err(TxRejection::Validation(Error::ZeroCoin)), Ok(_)] => {
+            debug!("batch validated with per-transaction results");
+        }
+        x => {
+            cleanup(&storage);
+            panic!("unexpected batch results: {:?}", x);
+        }
+    };
+    let sealedtx3 = match staged.get(&txid3) {
+        Some(tx) => tx.to_vec(),
+        None => {
+            cleanup(&storage);
+            panic!("batch tx not staged");
+        }
+    };
+    end_block(enclave.geteid(), IntraEnclaveRequest::EndBlock).expect("end block");
+    assert_eq!(
+        commit_block(
+            enclave.geteid(),
+            &[3u8; 32],
+            None,
+            &mut staged,
+            &audit,
+            &mut txdb,
+            &mut metadb,
+            &mut filterdb,
+            &mut spentdb,
+            &auditdb
+        ),
+        Ok(1)
+    );
+    assert!(staged.is_empty(), "committed txs still staged");
+    assert!(audit.is_empty(), "committed audit log entries still staged");
+    match txdb.get(&txid3) {
+        Ok(Some(ref tx)) if tx[..] == sealedtx3[..] => {
+            debug!("staged txs stored along with the app hash");
+        }
+        _ => {
+            cleanup(&storage);
+            panic!("staged tx not stored on commit");
+        }
+    };
+
+    assert!(init_sealing_policy(
+        enclave.geteid(),
+        SealingPolicy::with_key_policy(KEYPOLICY_MRENCLAVE),
+        &mut txdb,
+        &mut metadb,
+        &mut spentdb,
+        &mut auditdb
+    )
+    .is_ok());
+    let resealedtx = match txdb.get(&txid3) {
+        Ok(Some(tx)) => tx.to_vec(),
+        _ => {
+            cleanup(&storage);
+            panic!("resealed tx not in db");
+        }
+    };
+    assert!(resealedtx != sealedtx3, "tx not resealed");
+    assert!(metadb
+        .get(SEALING_MIGRATION_KEY)
+        .expect("storage")
+        .is_none());
+    let mut tx4 = Tx::new();
+    tx4.attributes = TxAttributes::new(TEST_NETWORK_ID);
+    tx4.add_input(TxoPointer::new(txid3, 0));
+    tx4.add_output(TxOut::new(eaddr.clone(), Coin::from(1000_0000u32)));
+    let request5 = IntraEnclaveRequest::ValidateTx {
+        request: Box::new(VerifyTxRequest {
+            tx: transfer_tx(&params, &tx4, &secret_key, &merkle_tree, [8u8; 12]),
+            account: None,
+            info,
+        }),
+        tx_inputs: Some(vec![resealedtx.clone()]),
+    };
+    let r6 = check_tx(
+        enclave.geteid(),
+        request5,
+        ValidationMode::Deliver,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    );
+    assert!(r6.is_ok(), "resealed input not accepted");
+
+    let (_, utxo_root) =
+        end_block(enclave.geteid(), IntraEnclaveRequest::EndBlock).expect("end block");
+    let filter = [1u8; 256];
+    assert_eq!(
+        commit_block(
+            enclave.geteid(),
+            &[1u8; 32],
+            Some(&filter),
+            &mut staged,
+            &audit,
+            &mut txdb,
+            &mut metadb,
+            &mut filterdb,
+            &mut spentdb,
+            &auditdb
+        ),
+        Ok(2)
+    );
+    assert!(check_checkpoint(enclave.geteid(), Some([1u8; 32]), &metadb).is_ok());
+    assert!(restore_spent_set(enclave.geteid(), &spentdb).is_ok());
+    assert!(restore_audit_log(enclave.geteid(), &auditdb, &audit).is_ok());
+    assert_eq!(
+        end_block(enclave.geteid(), IntraEnclaveRequest::EndBlock).map(|(_, root)| root),
+        Ok(utxo_root),
+        "unspent outputs commitment not kept in the checkpoint"
+    );
+    let mut other_tx4 = Tx::new();
+    other_tx4.attributes = TxAttributes::new(TEST_NETWORK_ID);
+    other_tx4.add_input(TxoPointer::new(txid3, 0));
+    other_tx4.add_output(TxOut::new(eaddr.clone(), Coin::from(999_0000u32)));
+    let reused_nonce = IntraEnclaveRequest::ValidateTx {
+        request: Box::new(VerifyTxRequest {
+            tx: transfer_tx(&params, &other_tx4, &secret_key, &merkle_tree, [8u8; 12]),
+            account: None,
+            info,
+        }),
+        tx_inputs: Some(vec![resealedtx.clone()]),
+    };
+    match check_tx(
+        enclave.geteid(),
+        reused_nonce,
+        ValidationMode::Check,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    ) {
+        Err(TxRejection::Enclave(EnclaveRejection::NonceReuse)) => {
+            debug!("payload nonce used in a restored block rejected");
+        }
+        x => {
+            cleanup(&storage);
+            panic!(
+                "payload nonce used in a restored block not rejected: {:?}",
+                x
+            );
+        }
+    };
+    let respend = IntraEnclaveRequest::ValidateTx {
+        request: Box::new(VerifyTxRequest {
+            tx: transfer_tx(&params, &tx4, &secret_key, &merkle_tree, [9u8; 12]),
+            account: None,
+            info,
+        }),
+        tx_inputs: Some(vec![resealedtx]),
+    };
+    match check_tx(
+        enclave.geteid(),
+        respend,
+        ValidationMode::Check,
+        &mut metadb,
+        &mut staged,
+        &audit,
+    ) {
+        Err(TxRejection::Enclave(EnclaveRejection::InputSpent)) => {
+            debug!("output spent in a committed block rejected");
+        }
+        x => {
+            cleanup(&storage);
+            panic!("output spent in a committed block not rejected: {:?}", x);
+        }
+    };
+    let sealed_spent = spentdb
+        .get(&1u64.to_be_bytes())
+        .expect("storage")
+        .expect("sealed spent outputs")
+        .to_vec();
+    let _ = spentdb.remove(&1u64.to_be_bytes());
+    assert!(
+        restore_spent_set(enclave.geteid(), &spentdb).is_err(),
+        "left out spent outputs accepted"
+    );
+    let _ = spentdb.insert(&1u64.to_be_bytes(), sealed_spent);
+    let sealedtx4 = txdb
+        .get(&tx4.id())
+        .expect("storage")
+        .expect("committed tx")
+        .to_vec();
+    let mut tx5 = Tx::new();
+    tx5.attributes = TxAttributes::new(TEST_NETWORK_ID);
+    tx5.add_input(TxoPointer::new(tx4.id(), 0));
+    tx5.add_output(TxOut::new(eaddr.clone(), Coin::from(1000_0000u32)));
+    for nonce in [[10u8; 12], [11u8; 12]].iter() {
+        let request = IntraEnclaveRequest::ValidateTx {
+            request: Box::new(VerifyTxRequest {
+                tx: transfer_tx(&params, &tx5, &secret_key, &merkle_tree, *nonce),
+                account: None,
+                info,
+            }),
+            tx_inputs: Some(vec![sealedtx4.clone()]),
+        };
+        match check_tx(
+            enclave.geteid(),
+            request,
+            ValidationMode::Deliver,
+            &mut metadb,
+            &mut staged,
+            &audit,
+        ) {
+            Ok(_) => {
+                debug!("tx delivered in a block to be abandoned");
+            }
+            x => {
+                cleanup(&storage);
+                panic!("output spent in an abandoned block not released: {:?}", x);
+            }
+        };
+        assert!(abandon_block(enclave.geteid(), &mut staged, &mut metadb).is_ok());
+        assert!(staged.is_empty(), "abandoned txs still staged");
+    }
+    assert_eq!(
+        check_checkpoint(enclave.geteid(), Some([2u8; 32]), &metadb),
+        Err(CheckpointError::AppHashMismatch)
+    );
+    let old_checkpoint = metadb
+        .get(CHECKPOINT_KEY)
+        .expect("storage")
+        .expect("sealed checkpoint")
+        .to_vec();
+    assert_eq!(
+        commit_block(
+            enclave.geteid(),
+            &[2u8; 32],
+            None,
+            &mut staged,
+            &audit,
+            &mut txdb,
+            &mut metadb,
+            &mut filterdb,
+            &mut spentdb,
+            &auditdb
+        ),
+        Ok(3)
+    );
+    match get_block_filters(&filterdb, 1, 10) {
+        Ok(ref filters)
+            if filters.len() == 1 && filters[0].0 == 2 && filters[0].1[..] == filter[..] =>
+        {
+            debug!("block filter stored");
+        }
+        _ => {
+            cleanup(&storage);
+            panic!("block filter not stored under the committed height");
+        }
+    };
+    // the host can't get a different genesis sealed by dropping the sealed one
+    let sealed_genesis = metadb
+        .get(GENESIS_KEY)
+        .expect("storage")
+        .expect("sealed genesis")
+        .to_vec();
+    let _ = metadb.remove(GENESIS_KEY);
+    assert!(
+        init_chain(
+            enclave.geteid(),
+            TEST_NETWORK_ID,
+            Some(&other_genesis),
+            &mut metadb
+        )
+        .is_err(),
+        "chain with committed blocks initialized again"
+    );
+    let _ = metadb.insert(GENESIS_KEY, sealed_genesis);
+    let audit_entries = auditdb.len().expect("audit entries") as u64;
+    assert!(audit_entries > 0, "validation decisions not logged");
+    assert_eq!(
+        verify_audit_log(enclave.geteid(), &auditdb, &audit),
+        Ok(audit_entries)
+    );
+    let (last_seq, last_entry) = auditdb.last().expect("storage").expect("audit log entry");
+    let mut tampered_entry = last_entry.to_vec();
+    let last = tampered_entry.len() - 1;
+    tampered_entry[last] ^= 0xff;
+    let _ = auditdb.insert(&last_seq, tampered_entry);
+    assert!(
+        verify_audit_log(enclave.geteid(), &auditdb, &audit).is_err(),
+        "tampered audit log accepted"
+    );
+    let _ = auditdb.remove(&last_seq);
+    assert!(
+        verify_audit_log(enclave.geteid(), &auditdb, &audit).is_err(),
+        "truncated audit log accepted"
+    );
+    let _ = auditdb.insert(&last_seq, last_entry);
+    assert_eq!(
+        verify_audit_log(enclave.geteid(), &auditdb, &audit),
+        Ok(audit_entries)
+    );
+    let _ = metadb.insert(CHECKPOINT_KEY, old_checkpoint);
+    assert_eq!(
+        check_checkpoint(enclave.geteid(), Some([1u8; 32]), &metadb),
+        Err(CheckpointError::RolledBack)
+    );
+    let mut tampered_checkpoint = metadb
+        .get(CHECKPOINT_KEY)
+        .expect("storage")
+        .expect("sealed checkpoint")
+        .to_vec();
+    let last = tampered_checkpoint.len() - 1;
+    tampered_checkpoint[last] ^= 0xff;
+    let _ = metadb.insert(CHECKPOINT_KEY, tampered_checkpoint);
+    assert_eq!(
+        check_checkpoint(enclave.geteid(), Some([1u8; 32]), &metadb),
+        Err(CheckpointError::Tampered)
+    );
+
+    assert_eq!(stored_version(&metadb), Ok(SCHEMA_VERSION));
+    // the chain metadata stored before the schema was versioned is moved out of the tx keyspace
+    let _ = metadb.remove(SCHEMA_VERSION_KEY);
+    let _ = metadb.remove(LAST_APP_HASH_KEY);
+    let _ = txdb.insert(LAST_APP_HASH_KEY, &[4u8; 32][..]);
+    assert!(migrate(&storage).is_ok());
+    assert_eq!(stored_version(&metadb), Ok(SCHEMA_VERSION));
+    assert!(
+        txdb.get(LAST_APP_HASH_KEY)
+            .map(|x| x.is_none())
+            .unwrap_or(false),
+        "last app hash left in the tx keyspace"
+    );
+    match metadb.get(LAST_APP_HASH_KEY) {
+        Ok(Some(ref app_hash)) if app_hash[..] == [4u8; 32][..] => {
+            debug!("last app hash moved to the meta keyspace");
+        }
+        _ => {
+            cleanup(&storage);
+            panic!("last app hash not moved to the meta keyspace");
+        }
+    };
+    let _ = metadb.insert(SCHEMA_VERSION_KEY, (SCHEMA_VERSION + 1).encode());
+    assert!(
+        migrate(&storage).is_err(),
+        "storage of a newer schema version accepted"
+    );
+
+    cleanup(&storage);
 }
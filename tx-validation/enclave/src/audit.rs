@@ -0,0 +1,186 @@
+//! # Audit log
+//! Every transaction validation decision is appended to a hash-chained log: each entry contains
+//! the hash of the previous one (the hash of an entry being `SHA-256` of its encoding),
+//! and it's sealed and stored by the host (along with the next committed block).
+//! The head of the log (the number of entries and the last hash) is kept in the sealed checkpoint,
+//! so the entries appended before the last committed block can't be left out.
+
+use crate::checkpoint;
+use crate::sealing::{seal, unseal};
+use chain_core::common::H256;
+use chain_core::state::tendermint::BlockHeight;
+use chain_core::tx::data::TxId;
+use chain_core::tx::fee::Fee;
+use enclave_protocol_ext::{TxRejection, ValidationMode};
+use lazy_static::lazy_static;
+use parity_scale_codec::{Decode, Encode};
+use sgx_tcrypto::rsgx_sha256_slice;
+use sgx_types::sgx_status_t;
+use std::prelude::v1::Vec;
+use std::sync::SgxRwLock;
+
+/// the additional (authenticated) data of the sealed log entries
+const AUDIT_SEALING_TAG: &[u8] = b"audit-entry";
+
+/// The number of entries and the hash of the last one
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AuditHead {
+    pub count: u64,
+    pub last_hash: H256,
+}
+
+/// the head of the empty log
+pub(crate) const EMPTY_HEAD: AuditHead = AuditHead {
+    count: 0,
+    last_hash: [0u8; 32],
+};
+
+#[derive(Encode, Decode)]
+pub(crate) enum AuditResult {
+    Accepted(Fee),
+    Rejected(TxRejection),
+}
+
+/// A validation decision to be logged
+#[derive(Encode, Decode)]
+pub(crate) struct Decision {
+    /// None if the request couldn't be decoded
+    pub txid: Option<TxId>,
+    pub mode: ValidationMode,
+    pub result: AuditResult,
+}
+
+#[derive(Encode, Decode)]
+struct AuditEntry {
+    seq: u64,
+    previous: H256,
+    /// the block being delivered (0 if the checkpoint wasn't checked yet)
+    height: BlockHeight,
+    decision: Decision,
+}
+
+lazy_static! {
+    static ref HEAD: SgxRwLock<Option<AuditHead>> = SgxRwLock::new(None);
+    /// the head of the entries passed back for verification so far
+    static ref VERIFYING: SgxRwLock<Option<AuditHead>> = SgxRwLock::new(None);
+}
+
+#[inline]
+fn unseal_entry(sealed_entry: &mut [u8]) -> Result<(AuditEntry, H256), sgx_status_t> {
+    let (tag, raw) = unseal(sealed_entry).ok_or(sgx_status_t::SGX_ERROR_MAC_MISMATCH)?;
+    if tag.as_slice() != AUDIT_SEALING_TAG {
+        return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+    }
+    let entry = AuditEntry::decode(&mut raw.as_slice())
+        .map_err(|_| sgx_status_t::SGX_ERROR_MAC_MISMATCH)?;
+    Ok((entry, rsgx_sha256_slice(&raw)?))
+}
+
+/// Seals the log entries of the decisions -- returns the new head and the sealed entries
+/// (with their sequence numbers). The head isn't updated until `set_head` is called
+/// (i.e. the sealed entries were handed over for storage).
+pub(crate) fn append(
+    decisions: Vec<Decision>,
+) -> Result<(AuditHead, Vec<(u64, Vec<u8>)>), sgx_status_t> {
+    let mut head = HEAD
+        .read()
+        .expect("poisoned lock: failed to get audit log")
+        .ok_or(sgx_status_t::SGX_ERROR_INVALID_STATE)?;
+    let height = checkpoint::next_height().unwrap_or(0);
+    let mut sealed_entries = Vec::with_capacity(decisions.len());
+    for decision in decisions.into_iter() {
+        let entry = AuditEntry {
+            seq: head.count,
+            previous: head.last_hash,
+            height,
+            decision,
+        };
+        let raw = entry.encode();
+        sealed_entries.push((entry.seq, seal(AUDIT_SEALING_TAG, &raw)?));
+        head = AuditHead {
+            count: head.count + 1,
+            last_hash: rsgx_sha256_slice(&raw)?,
+        };
+    }
+    Ok((head, sealed_entries))
+}
+
+pub(crate) fn set_head(head: AuditHead) {
+    *HEAD
+        .write()
+        .expect("poisoned lock: failed to get audit log") = Some(head);
+}
+
+/// The current head (None if the log wasn't restored yet)
+pub(crate) fn head() -> Option<AuditHead> {
+    *HEAD.read().expect("poisoned lock: failed to get audit log")
+}
+
+/// Continues the log from the last stored entry (None if the log is empty);
+/// it can't be behind the head in the checkpoint
+pub(crate) fn restore(last_entry: Option<&mut [u8]>) -> Result<(), sgx_status_t> {
+    let restored = match last_entry {
+        Some(sealed) => {
+            let (entry, hash) = unseal_entry(sealed)?;
+            AuditHead {
+                count: entry.seq + 1,
+                last_hash: hash,
+            }
+        }
+        None => EMPTY_HEAD,
+    };
+    let committed = checkpoint::audit_head().ok_or(sgx_status_t::SGX_ERROR_INVALID_STATE)?;
+    if restored.count < committed.count
+        || (restored.count == committed.count && restored != committed)
+    {
+        return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+    }
+    set_head(restored);
+    Ok(())
+}
+
+/// Checks the next stored entries (passed in the order of their sequence numbers, starting from 0) --
+/// returns the number of the entries checked so far
+pub(crate) fn verify(sealed_entries: Vec<Vec<u8>>) -> Result<u64, sgx_status_t> {
+    let mut verifying = VERIFYING
+        .write()
+        .expect("poisoned lock: failed to get audit log");
+    let mut state = verifying.unwrap_or(EMPTY_HEAD);
+    for mut sealed in sealed_entries.into_iter() {
+        let checked = unseal_entry(&mut sealed).and_then(|(entry, hash)| {
+            if entry.seq == state.count && entry.previous == state.last_hash {
+                Ok(hash)
+            } else {
+                Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH)
+            }
+        });
+        match checked {
+            Ok(hash) => {
+                state = AuditHead {
+                    count: state.count + 1,
+                    last_hash: hash,
+                };
+            }
+            Err(e) => {
+                *verifying = None;
+                return Err(e);
+            }
+        }
+    }
+    *verifying = Some(state);
+    Ok(state.count)
+}
+
+/// Checks that the verified entries end at the current head -- returns their number
+pub(crate) fn finish_verification() -> Result<u64, sgx_status_t> {
+    let verified = VERIFYING
+        .write()
+        .expect("poisoned lock: failed to get audit log")
+        .take()
+        .unwrap_or(EMPTY_HEAD);
+    match head() {
+        Some(head) if head == verified => Ok(verified.count),
+        Some(_) => Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH),
+        None => Err(sgx_status_t::SGX_ERROR_INVALID_STATE),
+    }
+}
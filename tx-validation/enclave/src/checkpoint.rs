@@ -0,0 +1,269 @@
+//! # Sealed chain checkpoint
+//! On every committed block, the enclave seals the block height, the app hash and a hash chain digest
+//! (`digest_n = SHA-256(digest_{n-1} || n || app_hash_n)`, `digest_0` being the genesis app hash),
+//! along with the digest of the spent outputs, the unspent outputs commitment, the audit log head
+//! and the hash of the chain genesis.
+//! On CheckChain, the stored checkpoint needs to unseal, match the last app hash reported by Tendermint
+//! and the genesis the enclave is bound to, and not be older than the last checkpoint adopted by the enclave:
+//! within the enclave's lifetime, it's compared with the adopted one, and across restarts, its counter value
+//! with the monotonic counter the checkpoints are bound to (see `counter`).
+//! A block is committed in two phases: the next checkpoint is prepared and handed over to the host,
+//! and it's only adopted once the host confirms it was stored (along with the block's data).
+
+use crate::audit::{self, AuditHead};
+use crate::counter;
+use crate::genesis;
+use crate::sealing::{seal, unseal};
+use crate::spent;
+use crate::utxo;
+use chain_core::common::H256;
+use chain_core::state::tendermint::BlockHeight;
+use lazy_static::lazy_static;
+use parity_scale_codec::{Decode, Encode};
+use sgx_tcrypto::rsgx_sha256_slice;
+use sgx_types::sgx_status_t;
+use std::prelude::v1::Vec;
+use std::sync::SgxRwLock;
+
+/// the additional (authenticated) data of the sealed checkpoint
+const CHECKPOINT_SEALING_TAG: &[u8] = b"chain-checkpoint";
+
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub(crate) struct Checkpoint {
+    pub height: BlockHeight,
+    pub app_hash: H256,
+    pub digest: H256,
+    pub spent_digest: H256,
+    pub utxo_root: H256,
+    pub audit_head: AuditHead,
+    pub genesis_hash: H256,
+    /// the value of the monotonic counter once the checkpoint is adopted
+    pub counter: u32,
+}
+
+enum ChainState {
+    /// CheckChain wasn't called yet
+    Unchecked,
+    /// no block was committed yet
+    Fresh,
+    Committed(Checkpoint),
+}
+
+lazy_static! {
+    static ref CHAIN_STATE: SgxRwLock<ChainState> = SgxRwLock::new(ChainState::Unchecked);
+    /// the next checkpoint handed over to the host (until it's confirmed or discarded)
+    static ref PREPARED: SgxRwLock<Option<Checkpoint>> = SgxRwLock::new(None);
+}
+
+/// Checks the stored checkpoint (None on a fresh chain) against the last app hash and the genesis
+/// (which needs to be bound before).
+/// Errors:
+/// * SGX_ERROR_MAC_MISMATCH: the checkpoint was tampered with (or it's for a different genesis)
+/// * SGX_ERROR_INVALID_STATE: the checkpoint was rolled back
+/// * SGX_ERROR_INVALID_PARAMETER: the checkpoint doesn't match the last app hash
+pub(crate) fn check(
+    sealed_checkpoint: Option<&mut [u8]>,
+    last_app_hash: Option<H256>,
+) -> Result<(), sgx_status_t> {
+    let mut state = CHAIN_STATE
+        .write()
+        .expect("poisoned lock: failed to get chain checkpoint");
+    let stored = match sealed_checkpoint {
+        Some(sealed) => {
+            let (tag, raw) = unseal(sealed).ok_or(sgx_status_t::SGX_ERROR_MAC_MISMATCH)?;
+            if tag.as_slice() != CHECKPOINT_SEALING_TAG {
+                return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+            }
+            Some(
+                Checkpoint::decode(&mut raw.as_slice())
+                    .map_err(|_| sgx_status_t::SGX_ERROR_MAC_MISMATCH)?,
+            )
+        }
+        None => None,
+    };
+    if let Some(checkpoint) = &stored {
+        let genesis = genesis::get().ok_or(sgx_status_t::SGX_ERROR_MAC_MISMATCH)?;
+        if checkpoint.genesis_hash != genesis::hash(&genesis)? {
+            return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+        }
+    }
+    let mut prepared = PREPARED
+        .write()
+        .expect("poisoned lock: failed to get prepared checkpoint");
+    if stored.is_some() && *prepared == stored {
+        // the host stored the prepared checkpoint, but didn't confirm it
+        if let Some(checkpoint) = prepared.take() {
+            spent::commit(checkpoint.spent_digest);
+            utxo::commit();
+            *state = ChainState::Committed(checkpoint);
+        }
+    }
+    *prepared = None;
+    match (&*state, &stored) {
+        (ChainState::Committed(_), None) => {
+            return Err(sgx_status_t::SGX_ERROR_INVALID_STATE);
+        }
+        (ChainState::Committed(current), Some(checkpoint)) if current != checkpoint => {
+            return Err(sgx_status_t::SGX_ERROR_INVALID_STATE);
+        }
+        _ => {}
+    }
+    if stored.as_ref().map(|c| c.app_hash) != last_app_hash {
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let expected = stored.as_ref().map_or(0, |c| c.counter);
+    match counter::read()? {
+        Some(value) if value == expected => {}
+        Some(value) if value + 1 == expected => {
+            // the host stored the checkpoint, but the enclave stopped before the commit was confirmed
+            if counter::increment()? != expected {
+                return Err(sgx_status_t::SGX_ERROR_INVALID_STATE);
+            }
+        }
+        Some(_) => {
+            return Err(sgx_status_t::SGX_ERROR_INVALID_STATE);
+        }
+        // no genesis (and thus no counter) is bound on a fresh chain before InitChain
+        None if stored.is_none() => {}
+        None => {
+            return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+        }
+    }
+    *state = match stored {
+        Some(checkpoint) => ChainState::Committed(checkpoint),
+        None => ChainState::Fresh,
+    };
+    Ok(())
+}
+
+/// Computes the next checkpoint -- returns it and its sealed form.
+/// The enclave's state isn't updated until `set` is called (i.e. the sealed form was stored by the host).
+pub(crate) fn next(
+    app_hash: H256,
+    spent_digest: H256,
+    utxo_root: H256,
+    audit_head: AuditHead,
+) -> Result<(Checkpoint, Vec<u8>), sgx_status_t> {
+    let state = CHAIN_STATE
+        .read()
+        .expect("poisoned lock: failed to get chain checkpoint");
+    let genesis = genesis::get().ok_or(sgx_status_t::SGX_ERROR_INVALID_STATE)?;
+    let (height, previous_digest, counter) = match &*state {
+        ChainState::Unchecked => {
+            return Err(sgx_status_t::SGX_ERROR_INVALID_STATE);
+        }
+        ChainState::Fresh => (1, genesis.genesis_app_hash, 1),
+        ChainState::Committed(checkpoint) => (
+            checkpoint.height + 1,
+            checkpoint.digest,
+            checkpoint.counter + 1,
+        ),
+    };
+    let mut to_hash = previous_digest.to_vec();
+    to_hash.extend(height.encode());
+    to_hash.extend(&app_hash);
+    let digest = rsgx_sha256_slice(&to_hash)?;
+    let checkpoint = Checkpoint {
+        height,
+        app_hash,
+        digest,
+        spent_digest,
+        utxo_root,
+        audit_head,
+        genesis_hash: genesis::hash(&genesis)?,
+        counter,
+    };
+    let sealed = seal(CHECKPOINT_SEALING_TAG, &checkpoint.encode())?;
+    Ok((checkpoint, sealed))
+}
+
+/// Whether a block was committed (in the enclave's lifetime or before, i.e. in the checked checkpoint)
+pub(crate) fn is_committed() -> bool {
+    match &*CHAIN_STATE
+        .read()
+        .expect("poisoned lock: failed to get chain checkpoint")
+    {
+        ChainState::Committed(_) => true,
+        _ => false,
+    }
+}
+
+/// The height of the block being delivered (None if the checkpoint wasn't checked yet)
+pub(crate) fn next_height() -> Option<BlockHeight> {
+    match &*CHAIN_STATE
+        .read()
+        .expect("poisoned lock: failed to get chain checkpoint")
+    {
+        ChainState::Unchecked => None,
+        ChainState::Fresh => Some(1),
+        ChainState::Committed(checkpoint) => Some(checkpoint.height + 1),
+    }
+}
+
+/// The digest of the committed spent outputs (None if the checkpoint wasn't checked yet)
+pub(crate) fn spent_digest() -> Option<H256> {
+    match &*CHAIN_STATE
+        .read()
+        .expect("poisoned lock: failed to get chain checkpoint")
+    {
+        ChainState::Unchecked => None,
+        ChainState::Fresh => Some(spent::EMPTY_DIGEST),
+        ChainState::Committed(checkpoint) => Some(checkpoint.spent_digest),
+    }
+}
+
+/// The committed unspent outputs commitment (None if the checkpoint wasn't checked yet)
+pub(crate) fn utxo_root() -> Option<H256> {
+    match &*CHAIN_STATE
+        .read()
+        .expect("poisoned lock: failed to get chain checkpoint")
+    {
+        ChainState::Unchecked => None,
+        ChainState::Fresh => Some(utxo::EMPTY_ROOT),
+        ChainState::Committed(checkpoint) => Some(checkpoint.utxo_root),
+    }
+}
+
+/// The audit log head at the last committed block (None if the checkpoint wasn't checked yet)
+pub(crate) fn audit_head() -> Option<AuditHead> {
+    match &*CHAIN_STATE
+        .read()
+        .expect("poisoned lock: failed to get chain checkpoint")
+    {
+        ChainState::Unchecked => None,
+        ChainState::Fresh => Some(audit::EMPTY_HEAD),
+        ChainState::Committed(checkpoint) => Some(checkpoint.audit_head),
+    }
+}
+
+pub(crate) fn set(checkpoint: Checkpoint) {
+    *CHAIN_STATE
+        .write()
+        .expect("poisoned lock: failed to get chain checkpoint") =
+        ChainState::Committed(checkpoint);
+}
+
+/// Keeps the next checkpoint until the host confirms it was stored (a previously prepared one is replaced)
+pub(crate) fn prepare(checkpoint: Checkpoint) {
+    *PREPARED
+        .write()
+        .expect("poisoned lock: failed to get prepared checkpoint") = Some(checkpoint);
+}
+
+/// Takes the prepared checkpoint if it's the one for the app hash
+pub(crate) fn take_prepared(app_hash: &H256) -> Option<Checkpoint> {
+    let mut prepared = PREPARED
+        .write()
+        .expect("poisoned lock: failed to get prepared checkpoint");
+    match &*prepared {
+        Some(checkpoint) if checkpoint.app_hash == *app_hash => prepared.take(),
+        _ => None,
+    }
+}
+
+/// Drops the prepared checkpoint (e.g. when the block it was prepared for was changed or abandoned)
+pub(crate) fn discard_prepared() {
+    *PREPARED
+        .write()
+        .expect("poisoned lock: failed to get prepared checkpoint") = None;
+}
@@ -0,0 +1,107 @@
+//! # Monotonic counter
+//! The checkpoints are bound to an SGX monotonic counter (created when the genesis is sealed,
+//! its id being sealed along with the genesis): it's incremented whenever a checkpoint is adopted,
+//! and the checkpoint keeps the value the counter has once it's adopted, so an older sealed checkpoint
+//! (or none at all) is rejected on CheckChain even after the enclave was restarted.
+//! The counter is only incremented after the host stored the checkpoint, so the stored one
+//! may be one ahead of the counter if the enclave stopped before the commit was confirmed.
+
+use lazy_static::lazy_static;
+use sgx_types::{
+    sgx_close_pse_session, sgx_create_monotonic_counter, sgx_create_pse_session,
+    sgx_increment_monotonic_counter, sgx_mc_uuid_t, sgx_read_monotonic_counter, sgx_status_t,
+};
+use std::sync::SgxRwLock;
+
+/// the raw `sgx_mc_uuid_t` (the counter id followed by its nonce)
+pub(crate) type CounterId = [u8; 16];
+
+lazy_static! {
+    static ref COUNTER: SgxRwLock<Option<sgx_mc_uuid_t>> = SgxRwLock::new(None);
+}
+
+#[inline]
+fn to_uuid(id: &CounterId) -> sgx_mc_uuid_t {
+    let mut uuid = sgx_mc_uuid_t::default();
+    uuid.counter_id.copy_from_slice(&id[..3]);
+    uuid.nonce.copy_from_slice(&id[3..]);
+    uuid
+}
+
+#[inline]
+fn to_id(uuid: &sgx_mc_uuid_t) -> CounterId {
+    let mut id = [0u8; 16];
+    id[..3].copy_from_slice(&uuid.counter_id);
+    id[3..].copy_from_slice(&uuid.nonce);
+    id
+}
+
+/// The counter operations need a session with the platform services enclave
+#[inline]
+fn in_pse_session<F>(op: F) -> Result<u32, sgx_status_t>
+where
+    F: FnOnce(&mut u32) -> sgx_status_t,
+{
+    let status = unsafe { sgx_create_pse_session() };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    let mut value: u32 = 0;
+    let status = op(&mut value);
+    unsafe {
+        sgx_close_pse_session();
+    }
+    if status == sgx_status_t::SGX_SUCCESS {
+        Ok(value)
+    } else {
+        Err(status)
+    }
+}
+
+/// Creates a new counter (starting from 0) and binds to it -- returns its id (to be sealed with the genesis)
+pub(crate) fn create() -> Result<CounterId, sgx_status_t> {
+    let mut uuid = sgx_mc_uuid_t::default();
+    in_pse_session(|value| unsafe { sgx_create_monotonic_counter(&mut uuid, value) })?;
+    *COUNTER
+        .write()
+        .expect("poisoned lock: failed to get monotonic counter") = Some(uuid);
+    Ok(to_id(&uuid))
+}
+
+/// Binds to the counter of the unsealed genesis
+pub(crate) fn bind(id: &CounterId) {
+    *COUNTER
+        .write()
+        .expect("poisoned lock: failed to get monotonic counter") = Some(to_uuid(id));
+}
+
+/// The id of the counter the enclave is bound to
+pub(crate) fn id() -> Option<CounterId> {
+    COUNTER
+        .read()
+        .expect("poisoned lock: failed to get monotonic counter")
+        .as_ref()
+        .map(to_id)
+}
+
+/// The current value (None if the enclave isn't bound to a counter yet, i.e. no genesis was sealed)
+pub(crate) fn read() -> Result<Option<u32>, sgx_status_t> {
+    let counter = *COUNTER
+        .read()
+        .expect("poisoned lock: failed to get monotonic counter");
+    match counter {
+        Some(uuid) => {
+            in_pse_session(|value| unsafe { sgx_read_monotonic_counter(&uuid, value) }).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Increments the counter -- returns the new value
+pub(crate) fn increment() -> Result<u32, sgx_status_t> {
+    let uuid = COUNTER
+        .read()
+        .expect("poisoned lock: failed to get monotonic counter")
+        .ok_or(sgx_status_t::SGX_ERROR_INVALID_STATE)?;
+    in_pse_session(|value| unsafe { sgx_increment_monotonic_counter(&uuid, value) })
+}
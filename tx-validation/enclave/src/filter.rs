@@ -0,0 +1,106 @@
+//! # Block filter
+//! The view keys of the transactions delivered in the current block are added to a bloom filter,
+//! which is returned (and reset) on EndBlock. After every delivered transaction, the filter is sealed
+//! along with the height of the block it's for, so that the host can store it
+//! and restore it if the enclave is restarted in the middle of a block.
+
+use crate::checkpoint;
+use crate::sealing::{seal, unseal};
+use chain_core::state::tendermint::BlockHeight;
+use chain_tx_filter::BlockFilter;
+use chain_tx_validation::TxWithOutputs;
+use lazy_static::lazy_static;
+use parity_scale_codec::{Decode, Encode};
+use sgx_types::sgx_status_t;
+use std::prelude::v1::Vec;
+use std::sync::SgxRwLock;
+
+/// the additional (authenticated) data of the sealed filter
+const FILTER_SEALING_TAG: &[u8] = b"block-filter";
+
+lazy_static! {
+    static ref FILTER: SgxRwLock<BlockFilter> = SgxRwLock::new(BlockFilter::default());
+}
+
+#[inline]
+fn add_view_keys(filter: &mut BlockFilter, wraptx: &TxWithOutputs) {
+    match wraptx {
+        TxWithOutputs::Transfer(tx) => {
+            for view in tx.attributes.allowed_view.iter() {
+                filter.add_view_key(&view.view_key);
+            }
+        }
+        TxWithOutputs::StakeWithdraw(tx) => {
+            for view in tx.attributes.allowed_view.iter() {
+                filter.add_view_key(&view.view_key);
+            }
+        }
+    }
+}
+
+/// Adds the view keys of the delivered transactions -- returns the updated filter and its sealed form
+/// (None if the block height isn't known yet, i.e. before the checkpoint was checked).
+/// The enclave's filter isn't changed until `set` is called (i.e. the host confirmed the delivery),
+/// so the caller needs to hold the update lock in between.
+pub(crate) fn next(
+    delivered: &[TxWithOutputs],
+) -> Result<(BlockFilter, Option<Vec<u8>>), sgx_status_t> {
+    let raw = FILTER
+        .read()
+        .expect("poisoned lock: failed to get block tx filter")
+        .get_raw();
+    let mut filter = BlockFilter::from(&raw[..]);
+    for wraptx in delivered.iter() {
+        add_view_keys(&mut filter, wraptx);
+    }
+    let sealed = match checkpoint::next_height() {
+        Some(height) => {
+            let to_seal: (BlockHeight, Vec<u8>) = (height, filter.get_raw().to_vec());
+            Some(seal(FILTER_SEALING_TAG, &to_seal.encode())?)
+        }
+        None => None,
+    };
+    Ok((filter, sealed))
+}
+
+pub(crate) fn set(filter: BlockFilter) {
+    *FILTER
+        .write()
+        .expect("poisoned lock: failed to get block tx filter") = filter;
+}
+
+/// The current block's filter
+pub(crate) fn get_raw() -> [u8; 256] {
+    FILTER
+        .read()
+        .expect("poisoned lock: failed to get block tx filter")
+        .get_raw()
+}
+
+pub(crate) fn reset() {
+    FILTER
+        .write()
+        .expect("poisoned lock: failed to get block tx filter")
+        .reset();
+}
+
+/// Restores the sealed filter if it's for the next block (a filter of an already committed block is ignored)
+pub(crate) fn restore(sealed_filter: &mut [u8]) -> Result<(), sgx_status_t> {
+    let (tag, raw) = unseal(sealed_filter).ok_or(sgx_status_t::SGX_ERROR_MAC_MISMATCH)?;
+    if tag.as_slice() != FILTER_SEALING_TAG {
+        return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+    }
+    let (height, raw_filter): (BlockHeight, Vec<u8>) =
+        Decode::decode(&mut raw.as_slice()).map_err(|_| sgx_status_t::SGX_ERROR_MAC_MISMATCH)?;
+    if raw_filter.len() != 256 {
+        return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+    }
+    match checkpoint::next_height() {
+        Some(next_height) if next_height == height => {
+            set(BlockFilter::from(raw_filter.as_slice()));
+            Ok(())
+        }
+        Some(_) => Ok(()),
+        None => Err(sgx_status_t::SGX_ERROR_INVALID_STATE),
+    }
+}
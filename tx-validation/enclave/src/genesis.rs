@@ -0,0 +1,110 @@
+//! # Chain genesis binding
+//! The first initialization seals the genesis (network id, genesis app hash and chain parameters);
+//! the sealed genesis is stored by the host and every later initialization needs to match it.
+//! The network id isn't fixed when the enclave is built, so the same enclave (and MRENCLAVE)
+//! can serve any network, but it can't be switched to another one once the genesis is sealed.
+//! The hash of the genesis is also kept in the sealed checkpoint, so if the host dropped the sealed genesis
+//! and initialized the chain again, the stored chain data would be rejected on CheckChain.
+//! The id of the monotonic counter the checkpoints are bound to is sealed along with the genesis.
+
+use crate::checkpoint;
+use crate::counter::{self, CounterId};
+use crate::sealing::{seal, unseal};
+use chain_core::common::H256;
+use enclave_protocol_ext::ChainGenesis;
+use lazy_static::lazy_static;
+use parity_scale_codec::{Decode, Encode};
+use sgx_tcrypto::rsgx_sha256_slice;
+use sgx_types::sgx_status_t;
+use std::prelude::v1::Vec;
+use std::sync::SgxRwLock;
+
+/// the additional (authenticated) data of the sealed genesis
+const GENESIS_SEALING_TAG: &[u8] = b"chain-genesis";
+
+lazy_static! {
+    static ref GENESIS: SgxRwLock<Option<ChainGenesis>> = SgxRwLock::new(None);
+}
+
+#[inline]
+fn unseal_genesis(sealed_genesis: &mut [u8]) -> Result<(ChainGenesis, CounterId), sgx_status_t> {
+    let (tag, raw) = unseal(sealed_genesis).ok_or(sgx_status_t::SGX_ERROR_INVALID_PARAMETER)?;
+    if tag.as_slice() != GENESIS_SEALING_TAG {
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    Decode::decode(&mut raw.as_slice()).map_err(|_| sgx_status_t::SGX_ERROR_INVALID_PARAMETER)
+}
+
+/// Checks the network id and the requested genesis (if any) against the sealed one (if any).
+/// Returns the newly sealed genesis if it's the first initialization with a genesis
+/// (which is rejected if a block was already committed); a new monotonic counter is created for it.
+pub(crate) fn init(
+    chain_hex_id: u8,
+    requested: Option<ChainGenesis>,
+    sealed_genesis: Option<&mut [u8]>,
+) -> Result<Option<Vec<u8>>, sgx_status_t> {
+    let stored = match sealed_genesis {
+        Some(sealed) => Some(unseal_genesis(sealed)?),
+        None => None,
+    };
+    let (genesis, counter_id) = match (stored, requested) {
+        (Some((stored, counter_id)), Some(requested)) => {
+            if stored != requested {
+                return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+            }
+            (stored, Some(counter_id))
+        }
+        (Some((stored, counter_id)), None) => (stored, Some(counter_id)),
+        (None, Some(requested)) => {
+            if checkpoint::is_committed() {
+                return Err(sgx_status_t::SGX_ERROR_INVALID_STATE);
+            }
+            (requested, None)
+        }
+        (None, None) => {
+            // a fresh chain: nothing to bind to until InitChain
+            return Ok(None);
+        }
+    };
+    if genesis.chain_hex_id != chain_hex_id {
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let mut current = GENESIS
+        .write()
+        .expect("poisoned lock: failed to get chain genesis");
+    if let Some(existing) = current.as_ref() {
+        if *existing != genesis {
+            return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+        }
+    }
+    let to_store = match counter_id {
+        Some(counter_id) => {
+            counter::bind(&counter_id);
+            None
+        }
+        None => {
+            // the counter created by an earlier initialization with the same genesis (whose sealed form
+            // wasn't stored) is reused
+            let counter_id = match (current.as_ref(), counter::id()) {
+                (Some(_), Some(counter_id)) => counter_id,
+                _ => counter::create()?,
+            };
+            Some(seal(GENESIS_SEALING_TAG, &(&genesis, counter_id).encode())?)
+        }
+    };
+    *current = Some(genesis);
+    Ok(to_store)
+}
+
+/// The genesis the enclave was bound to (None if it wasn't initialized yet)
+pub(crate) fn get() -> Option<ChainGenesis> {
+    GENESIS
+        .read()
+        .expect("poisoned lock: failed to get chain genesis")
+        .clone()
+}
+
+/// The hash of the encoded genesis (kept in the checkpoint)
+pub(crate) fn hash(genesis: &ChainGenesis) -> Result<H256, sgx_status_t> {
+    rsgx_sha256_slice(&genesis.encode())
+}
@@ -2,88 +2,662 @@
 #![crate_type = "staticlib"]
 #![cfg_attr(not(target_env = "sgx"), no_std)]
 #![cfg_attr(target_env = "sgx", feature(rustc_private))]
-#![feature(proc_macro_hygiene)]
 
 #[cfg(not(target_env = "sgx"))]
 #[macro_use]
 extern crate sgx_tstd as std;
 
-use chain_core::init::coin::Coin;
+use audit::{AuditResult, Decision};
+use chain_core::state::account::StakedState;
 use chain_core::tx::data::TxId;
-use chain_core::tx::fee::Fee;
+use chain_core::tx::fee::{Fee, FeeAlgorithm};
 use chain_core::tx::TransactionId;
-use chain_core::tx::{data::input::TxoIndex, PlainTxAux, TxAux, TxObfuscated};
+use chain_core::tx::{
+    data::input::{TxoIndex, TxoPointer},
+    PlainTxAux, TxAux,
+};
 use chain_tx_filter::BlockFilter;
 use chain_tx_validation::witness::verify_tx_recover_address;
 use chain_tx_validation::{
     verify_bonded_deposit_core, verify_transfer, verify_unbonded_withdraw_core, TxWithOutputs,
 };
-use enclave_macro::get_network_id;
 use enclave_protocol::{
     is_basic_valid_tx_request, IntraEnclaveRequest, IntraEnclaveResponse, IntraEnclaveResponseOk,
     VerifyTxRequest,
 };
+use enclave_protocol_ext::{
+    ChainGenesis, EnclaveRejection, SealingPolicy, TxRejection, ValidateTxResponse, ValidatedTx,
+    ValidationMode, ValidationStatement,
+};
 use lazy_static::lazy_static;
 use parity_scale_codec::{Decode, Encode};
-use sgx_tseal::SgxSealedData;
-use sgx_types::{sgx_sealed_data_t, sgx_status_t};
+use sgx_types::{sgx_report_t, sgx_status_t, sgx_target_info_t};
+use spent::UsedNonce;
 use std::prelude::v1::{Box, Vec};
 use std::slice;
-use std::sync::SgxMutex;
+use std::sync::{SgxMutex, SgxMutexGuard};
+
+/// hash-chained log of the validation decisions
+mod audit;
+/// sealed checkpoint of the last committed block
+mod checkpoint;
+/// the monotonic counter the checkpoints are bound to
+mod counter;
+/// the view key filter of the current block
+mod filter;
+/// binding to the chain genesis
+mod genesis;
+/// decryption of the transaction payloads
+mod obfuscate;
+/// helpers for (un)sealing data that the host stores
+mod sealing;
+/// signing of the validation results
+mod signing;
+/// the spent transaction outputs
+mod spent;
+/// the staked state transitions
+mod staking;
+/// the unspent outputs commitment
+mod utxo;
 
 lazy_static! {
-    static ref FILTER: SgxMutex<BlockFilter> = SgxMutex::new(BlockFilter::default());
+    /// Held while the current block's state (its filter, spent and created outputs) and the audit log head
+    /// are read and updated, so that concurrent ecalls don't overwrite each other's changes.
+    /// Deliveries hold it for the whole ecall, as they're validated against the current block's spent outputs;
+    /// mempool checks only take it for recording their decisions, so they can be validated in parallel.
+    static ref UPDATE: SgxMutex<()> = SgxMutex::new(());
+    /// the changes of the last delivery waiting for `ecall_confirm_delivery` (updated under the update lock)
+    static ref DELIVERY: SgxMutex<Option<Delivery>> = SgxMutex::new(None);
+}
+
+#[inline]
+fn lock_update() -> SgxMutexGuard<'static, ()> {
+    UPDATE
+        .lock()
+        .expect("poisoned lock: failed to get the update lock")
+}
+
+/// The current block's changes (its filter, spent and created outputs) of the transactions delivered in one ecall --
+/// they're only applied once the host stored the sealed filter (and staged the sealed transactions),
+/// so that a transaction the host failed to stage isn't counted in the block
+struct Delivery {
+    filter: BlockFilter,
+    spent: Vec<TxoPointer>,
+    nonces: Vec<UsedNonce>,
+    delta: utxo::Delta,
+}
+
+#[inline]
+fn set_delivery(delivery: Option<Delivery>) {
+    *DELIVERY
+        .lock()
+        .expect("poisoned lock: failed to get the delivery") = delivery;
+}
+
+/// The transactions delivered in one ecall, the outputs they spent, the payload nonces they used and the validation decisions --
+/// they're added to the audit log (and the delivery set aside for the host's confirmation)
+/// only after the response is written back
+#[derive(Default)]
+struct Processed {
+    txs: Vec<TxWithOutputs>,
+    spent: Vec<TxoPointer>,
+    nonces: Vec<UsedNonce>,
+    decisions: Vec<Decision>,
+}
+
+/// Sets the sealing key policy (`policy` is the encoded `SealingPolicy`) -- needs to be called before anything is sealed.
+/// `isv_svn` is set to the enclave's ISVSVN, which the sealing key is bound to.
+#[no_mangle]
+pub extern "C" fn ecall_set_sealing_policy(
+    policy: *const u8,
+    policy_len: usize,
+    isv_svn: *mut u16,
+) -> sgx_status_t {
+    let mut policy_slice = unsafe { slice::from_raw_parts(policy, policy_len) };
+    let policy = match SealingPolicy::decode(&mut policy_slice) {
+        Ok(policy) => policy,
+        Err(_) => {
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+    match sealing::set_policy(policy) {
+        Ok(svn) => {
+            unsafe {
+                *isv_svn = svn;
+            }
+            sgx_status_t::SGX_SUCCESS
+        }
+        Err(e) => e,
+    }
+}
+
+/// Reseals a blob sealed by this or an older enclave (e.g. with a lower ISVSVN or a different policy)
+/// with the current sealing key. `sealed_out_written` is set to the sealed length
+/// (if it doesn't fit in the buffer, the call can be retried with a larger one).
+#[no_mangle]
+pub extern "C" fn ecall_reseal(
+    sealed_in: *const u8,
+    sealed_in_len: usize,
+    sealed_out: *mut u8,
+    sealed_out_len: u32,
+    sealed_out_written: *mut u32,
+) -> sgx_status_t {
+    unsafe {
+        *sealed_out_written = 0;
+    }
+    let mut sealed = unsafe { slice::from_raw_parts(sealed_in, sealed_in_len) }.to_vec();
+    let resealed = match sealing::reseal(&mut sealed) {
+        Ok(resealed) => resealed,
+        Err(e) => {
+            return e;
+        }
+    };
+    unsafe {
+        *sealed_out_written = resealed.len() as u32;
+    }
+    if resealed.len() > sealed_out_len as usize {
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(resealed.as_ptr(), sealed_out, resealed.len());
+    }
+    sgx_status_t::SGX_SUCCESS
 }
 
-const NETWORK_HEX_ID: u8 = get_network_id!();
+/// Restores the block filter sealed after the last delivered transaction
+/// (it's ignored if it's for an already committed block); needs to be called after `ecall_check_checkpoint`
+#[no_mangle]
+pub extern "C" fn ecall_restore_filter(
+    sealed_filter: *const u8,
+    sealed_filter_len: usize,
+) -> sgx_status_t {
+    let mut sealed = unsafe { slice::from_raw_parts(sealed_filter, sealed_filter_len) }.to_vec();
+    match filter::restore(&mut sealed) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => e,
+    }
+}
 
-/// FIXME: genesis app_hash etc.
+/// Checks the network id against the genesis and binds the enclave to the chain genesis
+/// (the network isn't fixed when the enclave is built -- it's the one of the genesis sealed on the first initialization):
+/// * `genesis` (optional, i.e. `genesis_len` can be 0) is the encoded `ChainGenesis` sent on InitChain
+/// * `sealed_genesis` (optional) is the genesis sealed on the first initialization
+/// If there was no sealed genesis, the requested one is sealed and written back to `sealed_out`
+/// (`sealed_out_written` is set to its length, or 0 if nothing was sealed).
 #[no_mangle]
-pub extern "C" fn ecall_initchain(chain_hex_id: u8) -> sgx_status_t {
-    if chain_hex_id == NETWORK_HEX_ID {
-        sgx_status_t::SGX_SUCCESS
+pub extern "C" fn ecall_initchain(
+    chain_hex_id: u8,
+    genesis: *const u8,
+    genesis_len: usize,
+    sealed_genesis: *const u8,
+    sealed_genesis_len: usize,
+    sealed_out: *mut u8,
+    sealed_out_len: u32,
+    sealed_out_written: *mut u32,
+) -> sgx_status_t {
+    unsafe {
+        *sealed_out_written = 0;
+    }
+    let requested = if genesis_len > 0 {
+        let mut genesis_slice = unsafe { slice::from_raw_parts(genesis, genesis_len) };
+        match ChainGenesis::decode(&mut genesis_slice) {
+            Ok(g) => Some(g),
+            Err(_) => {
+                return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+            }
+        }
     } else {
-        sgx_status_t::SGX_ERROR_INVALID_PARAMETER
+        None
+    };
+    let mut stored = if sealed_genesis_len > 0 {
+        Some(unsafe { slice::from_raw_parts(sealed_genesis, sealed_genesis_len) }.to_vec())
+    } else {
+        None
+    };
+    match genesis::init(
+        chain_hex_id,
+        requested,
+        stored.as_mut().map(|x| x.as_mut_slice()),
+    ) {
+        Ok(Some(sealed)) => {
+            unsafe {
+                *sealed_out_written = sealed.len() as u32;
+            }
+            if sealed.len() > sealed_out_len as usize {
+                return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+            }
+            unsafe {
+                std::ptr::copy_nonoverlapping(sealed.as_ptr(), sealed_out, sealed.len());
+            }
+            sgx_status_t::SGX_SUCCESS
+        }
+        Ok(None) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => e,
     }
 }
 
-#[inline]
-fn unseal(sealed_log: &mut [u8]) -> Option<TxWithOutputs> {
-    if sealed_log.len() >= (std::u32::MAX as usize) {
-        return None;
+/// Restores the transaction obfuscation keys previously sealed by `ecall_rotate_obfuscation_key`
+#[no_mangle]
+pub extern "C" fn ecall_restore_obfuscation_keys(
+    sealed_keys: *const u8,
+    sealed_keys_len: usize,
+) -> sgx_status_t {
+    let mut sealed = unsafe { slice::from_raw_parts(sealed_keys, sealed_keys_len) }.to_vec();
+    match obfuscate::restore_keyring(&mut sealed) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => e,
+    }
+}
+
+/// Starts a new obfuscation key epoch and writes back all the (sealed) epoch keys.
+/// `sealed_keys_written` is set to the sealed length; if it doesn't fit in the buffer,
+/// the keys aren't rotated and the call can be retried with a larger buffer.
+#[no_mangle]
+pub extern "C" fn ecall_rotate_obfuscation_key(
+    key_from: u64,
+    sealed_keys: *mut u8,
+    sealed_keys_len: u32,
+    sealed_keys_written: *mut u32,
+) -> sgx_status_t {
+    let (keyring, sealed) = match obfuscate::next_keyring(key_from) {
+        Ok(x) => x,
+        Err(e) => {
+            return e;
+        }
+    };
+    unsafe {
+        *sealed_keys_written = sealed.len() as u32;
+    }
+    if sealed.len() > sealed_keys_len as usize {
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(sealed.as_ptr(), sealed_keys, sealed.len());
+    }
+    obfuscate::set_keyring(keyring);
+    sgx_status_t::SGX_SUCCESS
+}
+
+/// Restores the validation signing key previously sealed by `ecall_generate_signing_key`
+#[no_mangle]
+pub extern "C" fn ecall_restore_signing_key(
+    sealed_key: *const u8,
+    sealed_key_len: usize,
+) -> sgx_status_t {
+    let mut sealed = unsafe { slice::from_raw_parts(sealed_key, sealed_key_len) }.to_vec();
+    match signing::restore_key(&mut sealed) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => e,
     }
-    let opt = unsafe {
-        SgxSealedData::<[u8]>::from_raw_sealed_data_t(
-            sealed_log.as_mut_ptr() as *mut sgx_sealed_data_t,
-            sealed_log.len() as u32,
-        )
+}
+
+/// Generates the validation signing key (on the first start) and writes back its sealed form.
+/// `sealed_key_written` is set to the sealed length; if it doesn't fit in the buffer,
+/// the key isn't replaced and the call can be retried with a larger buffer.
+#[no_mangle]
+pub extern "C" fn ecall_generate_signing_key(
+    sealed_key: *mut u8,
+    sealed_key_len: u32,
+    sealed_key_written: *mut u32,
+) -> sgx_status_t {
+    let (secret_key, sealed) = match signing::generate_key() {
+        Ok(x) => x,
+        Err(e) => {
+            return e;
+        }
     };
-    let sealed_data = match opt {
-        Some(x) => x,
+    unsafe {
+        *sealed_key_written = sealed.len() as u32;
+    }
+    if sealed.len() > sealed_key_len as usize {
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(sealed.as_ptr(), sealed_key, sealed.len());
+    }
+    signing::set_key(secret_key);
+    sgx_status_t::SGX_SUCCESS
+}
+
+/// Returns the current obfuscation key epoch and its public key (for wallets to encrypt to)
+#[no_mangle]
+pub extern "C" fn ecall_get_encryption_params(
+    key_from: *mut u64,
+    public_key: *mut u8,
+) -> sgx_status_t {
+    match obfuscate::current_params() {
+        Some((current_key_from, current_public_key)) => {
+            unsafe {
+                *key_from = current_key_from;
+                std::ptr::copy_nonoverlapping(
+                    current_public_key.as_ptr(),
+                    public_key,
+                    current_public_key.len(),
+                );
+            }
+            sgx_status_t::SGX_SUCCESS
+        }
+        None => sgx_status_t::SGX_ERROR_INVALID_STATE,
+    }
+}
+
+/// Checks the checkpoint sealed on the last committed block against the last app hash
+/// (`sealed_checkpoint_len` and `last_app_hash_len` are 0 on a fresh chain).
+/// Returns SGX_ERROR_MAC_MISMATCH if the checkpoint was tampered with,
+/// SGX_ERROR_INVALID_STATE if it was rolled back
+/// and SGX_ERROR_INVALID_PARAMETER if it doesn't match the last app hash.
+#[no_mangle]
+pub extern "C" fn ecall_check_checkpoint(
+    sealed_checkpoint: *const u8,
+    sealed_checkpoint_len: usize,
+    last_app_hash: *const u8,
+    last_app_hash_len: usize,
+) -> sgx_status_t {
+    let mut stored = if sealed_checkpoint_len > 0 {
+        Some(unsafe { slice::from_raw_parts(sealed_checkpoint, sealed_checkpoint_len) }.to_vec())
+    } else {
+        None
+    };
+    let last_app_hash = match last_app_hash_len {
+        0 => None,
+        32 => {
+            let mut app_hash = [0u8; 32];
+            app_hash.copy_from_slice(unsafe { slice::from_raw_parts(last_app_hash, 32) });
+            Some(app_hash)
+        }
+        _ => {
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+    match checkpoint::check(stored.as_mut().map(|x| x.as_mut_slice()), last_app_hash) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => e,
+    }
+}
+
+/// Prepares the next checkpoint with the committed block's app hash (and the current audit log head) and writes back its sealed form
+/// (and the committed block's height), and the sealed outputs spent in the block.
+/// `sealed_checkpoint_written` and `sealed_spent_written` are set to the sealed lengths (the latter is 0
+/// if nothing was spent); if they don't fit in the buffers, the call can be retried with larger buffers.
+/// The checkpoint isn't advanced until the host stores the sealed data and calls `ecall_confirm_commit`
+/// (if the host fails to store them, the commit can be prepared again).
+#[no_mangle]
+pub extern "C" fn ecall_prepare_commit(
+    app_hash: *const u8,
+    sealed_checkpoint: *mut u8,
+    sealed_checkpoint_len: u32,
+    sealed_checkpoint_written: *mut u32,
+    height: *mut u64,
+    sealed_spent: *mut u8,
+    sealed_spent_len: u32,
+    sealed_spent_written: *mut u32,
+) -> sgx_status_t {
+    let mut committed_app_hash = [0u8; 32];
+    committed_app_hash.copy_from_slice(unsafe { slice::from_raw_parts(app_hash, 32) });
+    let _update = lock_update();
+    // a delivery the host didn't confirm isn't in the block
+    set_delivery(None);
+    let next_height = match checkpoint::next_height() {
+        Some(next_height) => next_height,
         None => {
-            return None;
+            return sgx_status_t::SGX_ERROR_INVALID_STATE;
         }
     };
-    let result = sealed_data.unseal_data();
-    let unsealed_data = match result {
+    let (spent_digest, spent) = match spent::next(next_height) {
         Ok(x) => x,
+        Err(e) => {
+            return e;
+        }
+    };
+    let utxo_root = match utxo::root() {
+        Some(utxo_root) => utxo_root,
+        None => {
+            return sgx_status_t::SGX_ERROR_INVALID_STATE;
+        }
+    };
+    let audit_head = match audit::head() {
+        Some(audit_head) => audit_head,
+        None => {
+            return sgx_status_t::SGX_ERROR_INVALID_STATE;
+        }
+    };
+    let (next, sealed) =
+        match checkpoint::next(committed_app_hash, spent_digest, utxo_root, audit_head) {
+            Ok(x) => x,
+            Err(e) => {
+                return e;
+            }
+        };
+    let spent = spent.unwrap_or_default();
+    unsafe {
+        *sealed_checkpoint_written = sealed.len() as u32;
+        *sealed_spent_written = spent.len() as u32;
+    }
+    if sealed.len() > sealed_checkpoint_len as usize || spent.len() > sealed_spent_len as usize {
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(sealed.as_ptr(), sealed_checkpoint, sealed.len());
+        if !spent.is_empty() {
+            std::ptr::copy_nonoverlapping(spent.as_ptr(), sealed_spent, spent.len());
+        }
+        *height = next.height;
+    }
+    checkpoint::prepare(next);
+    sgx_status_t::SGX_SUCCESS
+}
+
+/// Adopts the checkpoint prepared for the app hash (once the host stored it along with the block's data):
+/// the block's spent and created outputs are moved to the committed ones, and the monotonic counter is incremented
+#[no_mangle]
+pub extern "C" fn ecall_confirm_commit(app_hash: *const u8) -> sgx_status_t {
+    let mut committed_app_hash = [0u8; 32];
+    committed_app_hash.copy_from_slice(unsafe { slice::from_raw_parts(app_hash, 32) });
+    let _update = lock_update();
+    match checkpoint::take_prepared(&committed_app_hash) {
+        Some(next) => {
+            match counter::increment() {
+                Ok(value) if value == next.counter => {}
+                Ok(_) => {
+                    return sgx_status_t::SGX_ERROR_INVALID_STATE;
+                }
+                Err(e) => {
+                    // the commit can be confirmed again
+                    checkpoint::prepare(next);
+                    return e;
+                }
+            }
+            spent::commit(next.spent_digest);
+            utxo::commit();
+            checkpoint::set(next);
+            sgx_status_t::SGX_SUCCESS
+        }
+        None => sgx_status_t::SGX_ERROR_INVALID_STATE,
+    }
+}
+
+/// Adds the last delivery to the current block (once the host stored its sealed filter and staged the sealed transactions)
+#[no_mangle]
+pub extern "C" fn ecall_confirm_delivery() -> sgx_status_t {
+    let _update = lock_update();
+    let delivery = DELIVERY
+        .lock()
+        .expect("poisoned lock: failed to get the delivery")
+        .take();
+    match delivery {
+        Some(delivery) => {
+            filter::set(delivery.filter);
+            spent::add_pending(&delivery.spent, &delivery.nonces);
+            utxo::add_pending(delivery.delta);
+            // the block changed since its checkpoint was prepared
+            checkpoint::discard_prepared();
+            sgx_status_t::SGX_SUCCESS
+        }
+        None => sgx_status_t::SGX_ERROR_INVALID_STATE,
+    }
+}
+
+/// Discards the current block's changes (its filter, spent and created outputs, an unconfirmed delivery
+/// and a prepared checkpoint), e.g. when Tendermint replays the block after a restart
+#[no_mangle]
+pub extern "C" fn ecall_abandon_block() -> sgx_status_t {
+    let _update = lock_update();
+    set_delivery(None);
+    checkpoint::discard_prepared();
+    filter::reset();
+    spent::abandon();
+    utxo::commit();
+    sgx_status_t::SGX_SUCCESS
+}
+
+/// Writes back the unspent outputs commitment, including the changes of the current block
+/// (to be requested on EndBlock)
+#[no_mangle]
+pub extern "C" fn ecall_get_utxo_commitment(commitment: *mut u8) -> sgx_status_t {
+    match utxo::root() {
+        Some(root) => {
+            unsafe {
+                std::ptr::copy_nonoverlapping(root.as_ptr(), commitment, root.len());
+            }
+            sgx_status_t::SGX_SUCCESS
+        }
+        None => sgx_status_t::SGX_ERROR_INVALID_STATE,
+    }
+}
+
+/// Passes back the sealed spent outputs of the committed blocks (`sealed_spent` is the encoded `Vec<Vec<u8>>`,
+/// in the height order) -- can be called several times; needs to be called after `ecall_check_checkpoint`
+#[no_mangle]
+pub extern "C" fn ecall_restore_spent(
+    sealed_spent: *const u8,
+    sealed_spent_len: usize,
+) -> sgx_status_t {
+    let mut sealed_spent_slice = unsafe { slice::from_raw_parts(sealed_spent, sealed_spent_len) };
+    let sealed_blocks: Vec<Vec<u8>> = match Decode::decode(&mut sealed_spent_slice) {
+        Ok(sealed_blocks) => sealed_blocks,
         Err(_) => {
-            return None;
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+    match spent::restore(sealed_blocks) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => e,
+    }
+}
+
+/// Checks the spent outputs passed back in `ecall_restore_spent` against the checkpoint
+/// (SGX_ERROR_MAC_MISMATCH if some were left out or rolled back); transactions with inputs
+/// are refused until it succeeds
+#[no_mangle]
+pub extern "C" fn ecall_check_spent() -> sgx_status_t {
+    match spent::finish_restore() {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => e,
+    }
+}
+
+/// Continues the audit log from its last stored entry (`sealed_entry_len` is 0 if no entry was stored);
+/// needs to be called after `ecall_check_checkpoint`. Returns SGX_ERROR_MAC_MISMATCH if the entry
+/// was tampered with or is older than the audit log head in the checkpoint.
+#[no_mangle]
+pub extern "C" fn ecall_restore_audit_log(
+    sealed_entry: *const u8,
+    sealed_entry_len: usize,
+) -> sgx_status_t {
+    let mut stored = if sealed_entry_len > 0 {
+        Some(unsafe { slice::from_raw_parts(sealed_entry, sealed_entry_len) }.to_vec())
+    } else {
+        None
+    };
+    match audit::restore(stored.as_mut().map(|x| x.as_mut_slice())) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => e,
+    }
+}
+
+/// Passes back the next stored audit log entries for verification (`sealed_entries` is the encoded `Vec<Vec<u8>>`,
+/// in the sequence number order, starting from the first entry) -- can be called several times.
+/// Returns SGX_ERROR_MAC_MISMATCH (and the verification starts over) if an entry was tampered with or left out.
+#[no_mangle]
+pub extern "C" fn ecall_verify_audit_log(
+    sealed_entries: *const u8,
+    sealed_entries_len: usize,
+) -> sgx_status_t {
+    let mut sealed_entries_slice =
+        unsafe { slice::from_raw_parts(sealed_entries, sealed_entries_len) };
+    let sealed_entries: Vec<Vec<u8>> = match Decode::decode(&mut sealed_entries_slice) {
+        Ok(sealed_entries) => sealed_entries,
+        Err(_) => {
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
         }
     };
-    let otx = TxWithOutputs::decode(&mut unsealed_data.get_decrypt_txt());
-    // TODO: check decoded txid against unsealed_data.get_additional_txt?
+    match audit::verify(sealed_entries) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => e,
+    }
+}
+
+/// Checks that the entries passed back in `ecall_verify_audit_log` end at the current audit log head
+/// (SGX_ERROR_MAC_MISMATCH if the last ones were left out) and sets `verified` to their number
+#[no_mangle]
+pub extern "C" fn ecall_finish_audit_verification(verified: *mut u64) -> sgx_status_t {
+    match audit::finish_verification() {
+        Ok(count) => {
+            unsafe {
+                *verified = count;
+            }
+            sgx_status_t::SGX_SUCCESS
+        }
+        Err(e) => e,
+    }
+}
+
+/// Writes back the (compressed) public key the validation results are signed with and the enclave's report
+/// for `target_info` (its report data starts with the public key)
+#[no_mangle]
+pub extern "C" fn ecall_get_validation_key(
+    target_info: *const sgx_target_info_t,
+    public_key: *mut u8,
+    report: *mut sgx_report_t,
+) -> sgx_status_t {
+    let target_info = unsafe { &*target_info };
+    match signing::report(target_info) {
+        Ok((key, key_report)) => {
+            unsafe {
+                std::ptr::copy_nonoverlapping(key.as_ptr(), public_key, key.len());
+                *report = key_report;
+            }
+            sgx_status_t::SGX_SUCCESS
+        }
+        Err(e) => e,
+    }
+}
+
+/// Unseals the transaction stored under `txid` -- returns None if the sealed data
+/// doesn't authenticate `txid` (e.g. the host swapped the sealed blobs)
+#[inline]
+fn unseal(sealed_log: &mut [u8], txid: &TxId) -> Option<TxWithOutputs> {
+    let (sealed_txid, unsealed_data) = sealing::unseal(sealed_log)?;
+    if sealed_txid.as_slice() != &txid[..] {
+        return None;
+    }
+    let otx = TxWithOutputs::decode(&mut unsealed_data.as_slice());
     match otx {
         Ok(tx) => Some(tx),
         _ => None,
     }
 }
 
+/// Unseals all the transactions referenced by the inputs (in the same order);
+/// returns None if any of them fails
 #[inline]
-fn unseal_all(mut sealed_logs: Vec<Vec<u8>>) -> Option<Vec<TxWithOutputs>> {
+fn unseal_all(mut sealed_logs: Vec<Vec<u8>>, inputs: &[TxoPointer]) -> Option<Vec<TxWithOutputs>> {
+    if sealed_logs.len() != inputs.len() {
+        return None;
+    }
     let mut result = Vec::with_capacity(sealed_logs.len());
-    for sealed_log in sealed_logs.iter_mut() {
-        if let Some(tx) = unseal(sealed_log) {
+    for (sealed_log, input) in sealed_logs.iter_mut().zip(inputs.iter()) {
+        if let Some(tx) = unseal(sealed_log, &input.id) {
             result.push(tx);
         } else {
             return None;
@@ -92,86 +666,96 @@ fn unseal_all(mut sealed_logs: Vec<Vec<u8>>) -> Option<Vec<TxWithOutputs>> {
     Some(result)
 }
 
+/// The accepted transaction's fee and account along with their signature
 #[inline]
-fn add_view_keys(wraptx: &TxWithOutputs) {
-    let mut filter = FILTER
-        .lock()
-        .expect("poisoned lock: failed to get block tx filter");
-    match wraptx {
-        TxWithOutputs::Transfer(tx) => {
-            for view in tx.attributes.allowed_view.iter() {
-                filter.add_view_key(&view.view_key);
-            }
-        }
-        TxWithOutputs::StakeWithdraw(tx) => {
-            for view in tx.attributes.allowed_view.iter() {
-                filter.add_view_key(&view.view_key);
-            }
-        }
-    }
+fn validated_tx(
+    txid: &TxId,
+    paid_fee: Fee,
+    account: Option<StakedState>,
+    sealed_tx: Option<Vec<u8>>,
+) -> Result<ValidatedTx, sgx_status_t> {
+    let statement = ValidationStatement {
+        txid: *txid,
+        paid_fee,
+        account,
+    };
+    let signature = signing::sign(&statement)?;
+    Ok(ValidatedTx {
+        paid_fee,
+        account: statement.account,
+        sealed_tx,
+        signature,
+    })
 }
 
+/// In the check mode, nothing is sealed (the returned `sealed_tx` is None);
+/// in the deliver mode, the transaction (and the outputs it spent and its payload nonce) is pushed to `processed`
+/// (its view keys are added to the block filter once the response is written back)
 #[inline]
 fn construct_sealed_response(
     result: Result<Fee, chain_tx_validation::Error>,
     txid: &TxId,
     to_seal_tx: TxWithOutputs,
-) -> Result<IntraEnclaveResponse, sgx_status_t> {
-    let to_seal = to_seal_tx.encode();
+    spent_inputs: &[TxoPointer],
+    used_nonce: UsedNonce,
+    account: Option<StakedState>,
+    mode: ValidationMode,
+    processed: &mut Processed,
+) -> Result<Result<ValidatedTx, chain_tx_validation::Error>, sgx_status_t> {
     match result {
         Err(e) => Ok(Err(e)),
+        Ok(fee) if mode == ValidationMode::Check => validated_tx(txid, fee, account, None).map(Ok),
         Ok(fee) => {
-            let sealing_result = SgxSealedData::<[u8]>::seal_data(txid, &to_seal);
-            let sealed_data = match sealing_result {
-                Ok(x) => x,
-                Err(ret) => {
-                    return Err(ret);
-                }
-            };
-            let sealed_log_size = SgxSealedData::<[u8]>::calc_raw_sealed_data_size(
-                sealed_data.get_add_mac_txt_len(),
-                sealed_data.get_encrypt_txt_len(),
-            ) as usize;
-            let mut sealed_log: Vec<u8> = vec![0u8; sealed_log_size];
-
-            unsafe {
-                let sealed_r = sealed_data.to_raw_sealed_data_t(
-                    sealed_log.as_mut_ptr() as *mut sgx_sealed_data_t,
-                    sealed_log_size as u32,
-                );
-                if sealed_r.is_none() {
-                    return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
-                }
-            }
-            add_view_keys(&to_seal_tx);
-            Ok(Ok(IntraEnclaveResponseOk::TxWithOutputs {
-                paid_fee: fee,
-                sealed_tx: sealed_log,
-            }))
+            let sealed_log = sealing::seal(txid, &to_seal_tx.encode())?;
+            let validated = validated_tx(txid, fee, account, Some(sealed_log))?;
+            processed.txs.push(to_seal_tx);
+            processed.spent.extend_from_slice(spent_inputs);
+            processed.nonces.push(used_nonce);
+            Ok(Ok(validated))
         }
     }
 }
 
+/// in the deliver mode, the outputs spent by the deposit (and its payload nonce) are pushed to `processed`
 #[inline]
 fn construct_simple_response(
-    result: Result<Coin, chain_tx_validation::Error>,
-) -> Result<IntraEnclaveResponse, sgx_status_t> {
+    result: Result<StakedState, chain_tx_validation::Error>,
+    txid: &TxId,
+    fee: Fee,
+    spent_inputs: &[TxoPointer],
+    used_nonce: UsedNonce,
+    mode: ValidationMode,
+    processed: &mut Processed,
+) -> Result<Result<ValidatedTx, chain_tx_validation::Error>, sgx_status_t> {
     match result {
         Err(e) => Ok(Err(e)),
-        Ok(input_coins) => Ok(Ok(IntraEnclaveResponseOk::DepositStakeTx { input_coins })),
+        Ok(account) => {
+            let validated = validated_tx(txid, fee, Some(account), None)?;
+            if mode == ValidationMode::Deliver {
+                processed.spent.extend_from_slice(spent_inputs);
+                processed.nonces.push(used_nonce);
+            }
+            Ok(Ok(validated))
+        }
     }
 }
 
+/// Writes back the encoded response if it fits in the buffer;
+/// `response_written` is set to the encoded length, so that the call can be retried with a larger buffer
 #[inline]
-fn write_back_response(
-    response: Result<IntraEnclaveResponse, sgx_status_t>,
+fn write_back_response<T: Encode>(
+    response: Result<T, sgx_status_t>,
     response_buf: *mut u8,
     max_response_len: u32,
+    response_written: *mut u32,
 ) -> sgx_status_t {
     match response {
         Ok(r) => {
             let to_copy = r.encode();
             let resp_len = to_copy.len() as u32;
+            unsafe {
+                *response_written = resp_len;
+            }
             if resp_len > 0 && resp_len <= max_response_len {
                 unsafe {
                     std::ptr::copy_nonoverlapping(to_copy.as_ptr(), response_buf, to_copy.len());
@@ -185,130 +769,538 @@ fn write_back_response(
     }
 }
 
+/// Writes back the response, (if any transactions were delivered) the sealed block filter
+/// and the sealed audit log entries of the decisions (the encoded `Vec<(u64, Vec<u8>)>` of the sequence numbers
+/// and the entries); the decisions are only added to the audit log (and the delivered transactions' view keys,
+/// spent and created outputs set aside for `ecall_confirm_delivery`) if all of them fit in the buffers.
+/// The `*_written` lengths are set either way, so that the call can be retried with larger buffers.
+/// The caller needs to hold the update lock.
 #[inline]
-fn handle_validate_tx(
-    request: Box<VerifyTxRequest>,
-    tx_inputs: Option<Vec<Vec<u8>>>,
+fn write_back_processed<T: Encode>(
+    response: Result<T, sgx_status_t>,
+    processed: Processed,
     response_buf: *mut u8,
     response_len: u32,
+    response_written: *mut u32,
+    sealed_filter: *mut u8,
+    sealed_filter_len: u32,
+    sealed_filter_written: *mut u32,
+    sealed_audit: *mut u8,
+    sealed_audit_len: u32,
+    sealed_audit_written: *mut u32,
 ) -> sgx_status_t {
-    if is_basic_valid_tx_request(&request, &tx_inputs, NETWORK_HEX_ID).is_err() {
+    let next = if processed.txs.is_empty() {
+        None
+    } else {
+        match filter::next(&processed.txs) {
+            Ok(next) => Some(next),
+            Err(e) => {
+                return e;
+            }
+        }
+    };
+    let created: Vec<(TxId, usize)> = processed
+        .txs
+        .iter()
+        .map(|wraptx| match wraptx {
+            TxWithOutputs::Transfer(tx) => (tx.id(), tx.outputs.len()),
+            TxWithOutputs::StakeWithdraw(tx) => (tx.id(), tx.outputs.len()),
+        })
+        .collect();
+    let delta = match utxo::Delta::new(&created, &processed.spent) {
+        Ok(delta) => delta,
+        Err(e) => {
+            return e;
+        }
+    };
+    let audited = if processed.decisions.is_empty() {
+        None
+    } else {
+        match audit::append(processed.decisions) {
+            Ok((head, entries)) => Some((head, entries.encode())),
+            Err(e) => {
+                return e;
+            }
+        }
+    };
+    let response = match response {
+        Ok(r) => r.encode(),
+        Err(e) => {
+            return e;
+        }
+    };
+    let filter_len = match &next {
+        Some((_, Some(sealed))) => sealed.len(),
+        _ => 0,
+    };
+    let audit_len = audited
+        .as_ref()
+        .map(|(_, entries)| entries.len())
+        .unwrap_or(0);
+    // all the lengths are reported, so that the call can be retried with large enough buffers
+    unsafe {
+        *response_written = response.len() as u32;
+        *sealed_filter_written = filter_len as u32;
+        *sealed_audit_written = audit_len as u32;
+    }
+    if response.is_empty()
+        || response.len() > response_len as usize
+        || filter_len > sealed_filter_len as usize
+        || audit_len > sealed_audit_len as usize
+    {
         return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
     }
+    unsafe {
+        std::ptr::copy_nonoverlapping(response.as_ptr(), response_buf, response.len());
+    }
+    if let Some((head, entries)) = audited {
+        unsafe {
+            std::ptr::copy_nonoverlapping(entries.as_ptr(), sealed_audit, entries.len());
+        }
+        audit::set_head(head);
+    }
+    match next {
+        Some((next_filter, sealed)) => {
+            if let Some(sealed) = sealed {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(sealed.as_ptr(), sealed_filter, sealed.len());
+                }
+            }
+            set_delivery(Some(Delivery {
+                filter: next_filter,
+                spent: processed.spent,
+                nonces: processed.nonces,
+                delta,
+            }));
+        }
+        None => {
+            // nothing for the host to store (e.g. only deposits were delivered)
+            spent::add_pending(&processed.spent, &processed.nonces);
+            utxo::add_pending(delta);
+        }
+    }
+    sgx_status_t::SGX_SUCCESS
+}
+
+/// Validates the transaction and records the decision in `processed`
+/// (nothing is recorded if the ecall fails)
+#[inline]
+fn handle_validate_tx(
+    request: Box<VerifyTxRequest>,
+    tx_inputs: Option<Vec<Vec<u8>>>,
+    mode: ValidationMode,
+    processed: &mut Processed,
+) -> Result<ValidateTxResponse, sgx_status_t> {
+    let txid = request.tx.tx_id();
+    let response = validate_tx(request, tx_inputs, mode, processed)?;
+    let result = match &response {
+        Ok(Ok(validated)) => AuditResult::Accepted(validated.paid_fee),
+        Ok(Err(e)) => AuditResult::Rejected(TxRejection::Validation(e.clone())),
+        Err(rejection) => AuditResult::Rejected(TxRejection::Enclave(*rejection)),
+    };
+    processed.decisions.push(Decision {
+        txid: Some(txid),
+        mode,
+        result,
+    });
+    Ok(response)
+}
+
+#[inline]
+fn validate_tx(
+    request: Box<VerifyTxRequest>,
+    tx_inputs: Option<Vec<Vec<u8>>>,
+    mode: ValidationMode,
+    processed: &mut Processed,
+) -> Result<ValidateTxResponse, sgx_status_t> {
+    let genesis = match genesis::get() {
+        Some(genesis) => genesis,
+        None => {
+            return Ok(Err(EnclaveRejection::NotInitialized));
+        }
+    };
+    if request.info.chain_hex_id != genesis.chain_hex_id {
+        return Ok(Err(EnclaveRejection::WrongNetwork));
+    }
+    if is_basic_valid_tx_request(&request, &tx_inputs, genesis.chain_hex_id).is_err() {
+        return Ok(Err(EnclaveRejection::InvalidRequest));
+    }
+    if genesis.params.unbonding_period != request.info.unbonding_period {
+        return Ok(Err(EnclaveRejection::WrongChainParams));
+    }
+    match genesis.params.fee_policy.calculate_for_txaux(&request.tx) {
+        Ok(min_fee) if min_fee.to_coin() == request.info.min_fee_computed.to_coin() => {}
+        _ => {
+            return Ok(Err(EnclaveRejection::WrongFee));
+        }
+    }
     match (tx_inputs, request.tx) {
         (
             Some(sealed_inputs),
             TxAux::TransferTx {
                 txid,
-                payload: TxObfuscated { txpayload, .. },
+                inputs: input_pointers,
+                payload,
                 no_of_outputs,
-                ..
             },
         ) => {
-            // FIXME: decrypting
-            let plaintx = PlainTxAux::decode(&mut txpayload.as_slice());
-            let unsealed_inputs = unseal_all(sealed_inputs);
-            match (plaintx, unsealed_inputs) {
-                (Ok(PlainTxAux::TransferTx(tx, witness)), Some(inputs)) => {
-                    if tx.id() != txid || tx.outputs.len() as TxoIndex != no_of_outputs {
-                        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
-                    }
-                    let result = verify_transfer(&tx, &witness, request.info, inputs);
-                    let response =
-                        construct_sealed_response(result, &txid, TxWithOutputs::Transfer(tx));
-                    write_back_response(response, response_buf, response_len)
+            let (tx, witness) = match obfuscate::decrypt(&payload, &txid) {
+                Ok(PlainTxAux::TransferTx(tx, witness)) => (tx, witness),
+                Ok(_) => {
+                    return Ok(Err(EnclaveRejection::PayloadDecode));
                 }
-                _ => {
-                    return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+                Err(rejection) => {
+                    return Ok(Err(rejection));
                 }
+            };
+            if tx.id() != txid {
+                return Ok(Err(EnclaveRejection::TxIdMismatch));
+            }
+            if tx.inputs != input_pointers {
+                return Ok(Err(EnclaveRejection::InputsMismatch));
+            }
+            if tx.outputs.len() as TxoIndex != no_of_outputs {
+                return Ok(Err(EnclaveRejection::OutputCountMismatch));
+            }
+            let used_nonce = (payload.key_from, payload.nonce, txid);
+            if let Err(rejection) = spent::check_nonce(&used_nonce, &processed.nonces) {
+                return Ok(Err(rejection));
             }
+            if let Err(rejection) = spent::check(&input_pointers, &processed.spent) {
+                return Ok(Err(rejection));
+            }
+            let inputs = match unseal_all(sealed_inputs, &input_pointers) {
+                Some(inputs) => inputs,
+                None => {
+                    return Ok(Err(EnclaveRejection::InputUnseal));
+                }
+            };
+            let result = verify_transfer(&tx, &witness, request.info, inputs);
+            construct_sealed_response(
+                result,
+                &txid,
+                TxWithOutputs::Transfer(tx),
+                &input_pointers,
+                used_nonce,
+                None,
+                mode,
+                processed,
+            )
+            .map(Ok)
         }
-        (
-            Some(sealed_inputs),
-            TxAux::DepositStakeTx {
-                tx,
-                payload: TxObfuscated { txpayload, .. },
-            },
-        ) => {
-            // FIXME: decrypting
-            let plaintx = PlainTxAux::decode(&mut txpayload.as_slice());
-            let inputs = unseal_all(sealed_inputs);
-            match (plaintx, inputs) {
-                (Ok(PlainTxAux::DepositStakeTx(witness)), Some(inputs)) => {
-                    let result = verify_bonded_deposit_core(&tx, &witness, request.info, inputs);
-                    let response = construct_simple_response(result);
-                    write_back_response(response, response_buf, response_len)
+        (Some(sealed_inputs), TxAux::DepositStakeTx { tx, payload }) => {
+            let txid = tx.id();
+            let witness = match obfuscate::decrypt(&payload, &txid) {
+                Ok(PlainTxAux::DepositStakeTx(witness)) => witness,
+                Ok(_) => {
+                    return Ok(Err(EnclaveRejection::PayloadDecode));
                 }
-                _ => {
-                    return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+                Err(rejection) => {
+                    return Ok(Err(rejection));
                 }
+            };
+            let used_nonce = (payload.key_from, payload.nonce, txid);
+            if let Err(rejection) = spent::check_nonce(&used_nonce, &processed.nonces) {
+                return Ok(Err(rejection));
             }
+            if let Err(rejection) = spent::check(&tx.inputs, &processed.spent) {
+                return Ok(Err(rejection));
+            }
+            let inputs = match unseal_all(sealed_inputs, &tx.inputs) {
+                Some(inputs) => inputs,
+                None => {
+                    return Ok(Err(EnclaveRejection::InputUnseal));
+                }
+            };
+            let info = request.info;
+            let account = request.account;
+            let result = verify_bonded_deposit_core(&tx, &witness, info, inputs)
+                .and_then(|input_coins| staking::deposit(account, &tx, input_coins, &info));
+            construct_simple_response(
+                result,
+                &txid,
+                info.min_fee_computed,
+                &tx.inputs,
+                used_nonce,
+                mode,
+                processed,
+            )
+            .map(Ok)
         }
         (
             None,
             TxAux::WithdrawUnbondedStakeTx {
                 txid,
                 no_of_outputs,
-                payload: TxObfuscated { txpayload, .. },
+                payload,
                 witness,
             },
         ) => {
-            let address = verify_tx_recover_address(&witness, &txid);
-            if address.is_err() {
-                return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
-            }
-            // FIXME: decrypting
-            let plaintx = PlainTxAux::decode(&mut txpayload.as_slice());
-            match (plaintx, request.account) {
-                (Ok(PlainTxAux::WithdrawUnbondedStakeTx(tx)), Some(account)) => {
-                    if tx.id() != txid
-                        || no_of_outputs != tx.outputs.len() as TxoIndex
-                        || account.address != address.unwrap()
-                    {
-                        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
-                    }
-                    let result = verify_unbonded_withdraw_core(&tx, request.info, &account);
-                    let response =
-                        construct_sealed_response(result, &txid, TxWithOutputs::StakeWithdraw(tx));
-                    write_back_response(response, response_buf, response_len)
+            let address = match verify_tx_recover_address(&witness, &txid) {
+                Ok(address) => address,
+                Err(_) => {
+                    return Ok(Err(EnclaveRejection::InvalidWitness));
                 }
-                _ => {
-                    return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+            };
+            let account = match request.account {
+                Some(account) => account,
+                None => {
+                    return Ok(Err(EnclaveRejection::MissingAccount));
                 }
+            };
+            let tx = match obfuscate::decrypt(&payload, &txid) {
+                Ok(PlainTxAux::WithdrawUnbondedStakeTx(tx)) => tx,
+                Ok(_) => {
+                    return Ok(Err(EnclaveRejection::PayloadDecode));
+                }
+                Err(rejection) => {
+                    return Ok(Err(rejection));
+                }
+            };
+            if tx.id() != txid {
+                return Ok(Err(EnclaveRejection::TxIdMismatch));
             }
+            if no_of_outputs != tx.outputs.len() as TxoIndex {
+                return Ok(Err(EnclaveRejection::OutputCountMismatch));
+            }
+            if account.address != address {
+                return Ok(Err(EnclaveRejection::WitnessAddressMismatch));
+            }
+            let used_nonce = (payload.key_from, payload.nonce, txid);
+            if let Err(rejection) = spent::check_nonce(&used_nonce, &processed.nonces) {
+                return Ok(Err(rejection));
+            }
+            let (result, account) = match verify_unbonded_withdraw_core(&tx, request.info, &account)
+                .and_then(|fee| staking::withdraw(account, &tx, fee).map(|account| (fee, account)))
+            {
+                Ok((fee, account)) => (Ok(fee), Some(account)),
+                Err(e) => (Err(e), None),
+            };
+            construct_sealed_response(
+                result,
+                &txid,
+                TxWithOutputs::StakeWithdraw(tx),
+                &[],
+                used_nonce,
+                account,
+                mode,
+                processed,
+            )
+            .map(Ok)
         }
-        (_, _) => {
-            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
-        }
+        (_, _) => Ok(Err(EnclaveRejection::InvalidRequest)),
     }
 }
 
+/// `mode` is the `ValidationMode` of `ValidateTx` requests (0: mempool check, 1: block delivery).
+/// A `ValidateTx` request is replied with `ValidateTxResponse`, i.e. `Err(EnclaveRejection)`
+/// if the enclave refused to validate the transaction; `EndBlock` is replied with `IntraEnclaveResponse`.
+/// `response_written` is set to the response length -- if it doesn't fit in the buffer,
+/// the block filter isn't updated or reset, so the call can be retried with a larger buffer.
+/// If a transaction was delivered, the updated block filter is sealed to `sealed_filter`
+/// (`sealed_filter_written` is set to its length, or 0 if nothing was sealed) -- the delivery is only
+/// added to the current block when the host calls `ecall_confirm_delivery` after storing it
+/// (it's discarded by the next delivery otherwise).
+/// The sealed audit log entry of the decision is written back to `sealed_audit`
+/// (`sealed_audit_written` is set to its length, or 0 on `EndBlock`); it needs to be stored by the host
+/// before the next block is committed.
+/// Mempool checks (`mode` 0) can be called concurrently; deliveries are processed one at a time.
 #[no_mangle]
 pub extern "C" fn ecall_check_tx(
+    mode: u8,
     tx_request: *const u8,
     tx_request_len: usize,
     response_buf: *mut u8,
     response_len: u32,
+    response_written: *mut u32,
+    sealed_filter: *mut u8,
+    sealed_filter_len: u32,
+    sealed_filter_written: *mut u32,
+    sealed_audit: *mut u8,
+    sealed_audit_len: u32,
+    sealed_audit_written: *mut u32,
 ) -> sgx_status_t {
+    unsafe {
+        *response_written = 0;
+        *sealed_filter_written = 0;
+        *sealed_audit_written = 0;
+    }
+    let mode = match ValidationMode::from_u8(mode) {
+        Some(mode) => mode,
+        None => {
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
     let mut tx_request_slice = unsafe { slice::from_raw_parts(tx_request, tx_request_len) };
     match IntraEnclaveRequest::decode(&mut tx_request_slice) {
+        Ok(IntraEnclaveRequest::ValidateTx { .. }) if audit::head().is_none() => {
+            // the decision can't be logged before the audit log is restored (on CheckChain)
+            let response: ValidateTxResponse = Err(EnclaveRejection::NotInitialized);
+            let _update = lock_update();
+            write_back_processed(
+                Ok(response),
+                Processed::default(),
+                response_buf,
+                response_len,
+                response_written,
+                sealed_filter,
+                sealed_filter_len,
+                sealed_filter_written,
+                sealed_audit,
+                sealed_audit_len,
+                sealed_audit_written,
+            )
+        }
         Ok(IntraEnclaveRequest::ValidateTx { request, tx_inputs }) => {
-            handle_validate_tx(request, tx_inputs, response_buf, response_len)
+            let delivering = if mode == ValidationMode::Deliver {
+                Some(lock_update())
+            } else {
+                None
+            };
+            let mut processed = Processed::default();
+            let response = handle_validate_tx(request, tx_inputs, mode, &mut processed);
+            let _update = delivering.unwrap_or_else(lock_update);
+            write_back_processed(
+                response,
+                processed,
+                response_buf,
+                response_len,
+                response_written,
+                sealed_filter,
+                sealed_filter_len,
+                sealed_filter_written,
+                sealed_audit,
+                sealed_audit_len,
+                sealed_audit_written,
+            )
         }
-        Ok(IntraEnclaveRequest::EndBlock) => {
-            let mut filter = FILTER
-                .lock()
-                .expect("poisoned lock: failed to get block tx filter");
-            let payload: [u8; 256] = filter.get_raw();
-            filter.reset();
-            write_back_response(
-                Ok(Ok(IntraEnclaveResponseOk::EndBlock(Box::new(payload)))),
+        Err(_) => {
+            let response: ValidateTxResponse = Err(EnclaveRejection::RequestDecode);
+            let mut processed = Processed::default();
+            processed.decisions.push(Decision {
+                txid: None,
+                mode,
+                result: AuditResult::Rejected(TxRejection::Enclave(
+                    EnclaveRejection::RequestDecode,
+                )),
+            });
+            let _update = lock_update();
+            write_back_processed(
+                Ok(response),
+                processed,
                 response_buf,
                 response_len,
+                response_written,
+                sealed_filter,
+                sealed_filter_len,
+                sealed_filter_written,
+                sealed_audit,
+                sealed_audit_len,
+                sealed_audit_written,
             )
         }
-        _ => {
+        Ok(IntraEnclaveRequest::EndBlock) => {
+            let _update = lock_update();
+            let payload: [u8; 256] = filter::get_raw();
+            let response: IntraEnclaveResponse =
+                Ok(IntraEnclaveResponseOk::EndBlock(Box::new(payload)));
+            let status =
+                write_back_response(Ok(response), response_buf, response_len, response_written);
+            if status == sgx_status_t::SGX_SUCCESS {
+                filter::reset();
+            }
+            status
+        }
+    }
+}
+
+/// Validates several transactions (`IntraEnclaveRequest::ValidateTx`) for block delivery in one enclave entry
+/// and writes back their responses (in the same order).
+/// A rejected transaction doesn't fail the whole batch -- the responses are `ValidateTxResponse`s.
+/// The transactions need to be independent: their inputs can't be outputs of a transaction in the same batch.
+/// `response_written`, the sealed filter and the sealed audit log entries (one per transaction)
+/// are set as in `ecall_check_tx`.
+#[no_mangle]
+pub extern "C" fn ecall_check_tx_batch(
+    tx_requests: *const u8,
+    tx_requests_len: usize,
+    response_buf: *mut u8,
+    response_len: u32,
+    response_written: *mut u32,
+    sealed_filter: *mut u8,
+    sealed_filter_len: u32,
+    sealed_filter_written: *mut u32,
+    sealed_audit: *mut u8,
+    sealed_audit_len: u32,
+    sealed_audit_written: *mut u32,
+) -> sgx_status_t {
+    unsafe {
+        *response_written = 0;
+        *sealed_filter_written = 0;
+        *sealed_audit_written = 0;
+    }
+    let mut tx_requests_slice = unsafe { slice::from_raw_parts(tx_requests, tx_requests_len) };
+    let requests: Vec<IntraEnclaveRequest> = match Decode::decode(&mut tx_requests_slice) {
+        Ok(requests) => requests,
+        Err(_) => {
             return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
         }
+    };
+    let mut responses: Vec<ValidateTxResponse> = Vec::with_capacity(requests.len());
+    let mut processed = Processed::default();
+    let _update = lock_update();
+    if audit::head().is_none() {
+        // the decisions can't be logged before the audit log is restored (on CheckChain)
+        responses.extend(
+            requests
+                .iter()
+                .map(|_| Err(EnclaveRejection::NotInitialized)),
+        );
+        return write_back_processed(
+            Ok(responses),
+            processed,
+            response_buf,
+            response_len,
+            response_written,
+            sealed_filter,
+            sealed_filter_len,
+            sealed_filter_written,
+            sealed_audit,
+            sealed_audit_len,
+            sealed_audit_written,
+        );
+    }
+    for request in requests.into_iter() {
+        match request {
+            IntraEnclaveRequest::ValidateTx { request, tx_inputs } => {
+                match handle_validate_tx(
+                    request,
+                    tx_inputs,
+                    ValidationMode::Deliver,
+                    &mut processed,
+                ) {
+                    Ok(response) => responses.push(response),
+                    Err(e) => {
+                        return e;
+                    }
+                }
+            }
+            _ => {
+                return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+            }
+        }
     }
+    write_back_processed(
+        Ok(responses),
+        processed,
+        response_buf,
+        response_len,
+        response_written,
+        sealed_filter,
+        sealed_filter_len,
+        sealed_filter_written,
+        sealed_audit,
+        sealed_audit_len,
+        sealed_audit_written,
+    )
 }
@@ -0,0 +1,169 @@
+//! # Transaction payload decryption
+//! The enclave keeps one secp256k1 key pair per key epoch, identified by `key_from`
+//! (the block height from which the key is used); older epochs are kept for decryption.
+//! The payload is `ephemeral public key (33 bytes) || ciphertext || authentication tag`:
+//! the AES-128-GCM key is derived via ECDH between the ephemeral key and the epoch key,
+//! `TxObfuscated.nonce` is the IV and the transaction id is the additional authenticated data.
+//! The nonces used by the delivered transactions are kept (and sealed) along with the spent outputs,
+//! and a nonce reused for a different transaction is rejected (see `spent::check_nonce`).
+
+use crate::sealing::{seal, unseal};
+use chain_core::state::tendermint::BlockHeight;
+use chain_core::tx::data::TxId;
+use chain_core::tx::{PlainTxAux, TxObfuscated};
+use enclave_protocol_ext::EnclaveRejection;
+use lazy_static::lazy_static;
+use parity_scale_codec::{Decode, Encode};
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::{
+    key::{PublicKey, SecretKey},
+    All, Secp256k1,
+};
+use sgx_tcrypto::rsgx_rijndael128GCM_decrypt;
+use sgx_trts::trts::rsgx_read_rand;
+use sgx_types::{
+    sgx_aes_gcm_128bit_key_t, sgx_aes_gcm_128bit_tag_t, sgx_status_t, SGX_AESGCM_MAC_SIZE,
+};
+use std::collections::BTreeMap;
+use std::prelude::v1::Vec;
+use std::sync::SgxRwLock;
+
+/// key_from => the epoch secret key
+pub(crate) type Keyring = BTreeMap<BlockHeight, SecretKey>;
+
+/// the compressed ephemeral public key prepended to the payload
+const EPHEMERAL_KEY_LEN: usize = 33;
+
+/// the additional (authenticated) data of the sealed keyring
+const KEYRING_SEALING_TAG: &[u8] = b"obfuscation-keys";
+
+lazy_static! {
+    static ref SECP: Secp256k1<All> = Secp256k1::new();
+    static ref KEYRING: SgxRwLock<Keyring> = SgxRwLock::new(Keyring::new());
+}
+
+#[inline]
+fn seal_keyring(keyring: &Keyring) -> Result<Vec<u8>, sgx_status_t> {
+    let raw_keys: Vec<(BlockHeight, [u8; 32])> = keyring
+        .iter()
+        .map(|(key_from, secret_key)| {
+            let mut raw_key = [0u8; 32];
+            raw_key.copy_from_slice(&secret_key[..]);
+            (*key_from, raw_key)
+        })
+        .collect();
+    seal(KEYRING_SEALING_TAG, &raw_keys.encode())
+}
+
+/// Replaces the current keyring with the one in the sealed blob (e.g. after the enclave restarted)
+pub(crate) fn restore_keyring(sealed_keyring: &mut [u8]) -> Result<(), sgx_status_t> {
+    let (tag, raw) = unseal(sealed_keyring).ok_or(sgx_status_t::SGX_ERROR_INVALID_PARAMETER)?;
+    if tag.as_slice() != KEYRING_SEALING_TAG {
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let raw_keys: Vec<(BlockHeight, [u8; 32])> = Decode::decode(&mut raw.as_slice())
+        .map_err(|_| sgx_status_t::SGX_ERROR_INVALID_PARAMETER)?;
+    let mut keyring = Keyring::new();
+    for (key_from, raw_key) in raw_keys.iter() {
+        let secret_key = SecretKey::from_slice(&raw_key[..])
+            .map_err(|_| sgx_status_t::SGX_ERROR_INVALID_PARAMETER)?;
+        keyring.insert(*key_from, secret_key);
+    }
+    if keyring.is_empty() {
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    *KEYRING
+        .write()
+        .expect("poisoned lock: failed to get obfuscation keys") = keyring;
+    Ok(())
+}
+
+/// Generates a new epoch key used from `key_from` onwards -- returns the new keyring and its sealed form.
+/// The keyring isn't replaced until `set_keyring` is called (i.e. the sealed form was handed over for storage).
+pub(crate) fn next_keyring(key_from: BlockHeight) -> Result<(Keyring, Vec<u8>), sgx_status_t> {
+    let mut keyring = KEYRING
+        .read()
+        .expect("poisoned lock: failed to get obfuscation keys")
+        .clone();
+    if let Some((last_key_from, _)) = keyring.iter().next_back() {
+        if *last_key_from >= key_from {
+            return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+        }
+    }
+    let mut raw_key = [0u8; 32];
+    let secret_key = loop {
+        rsgx_read_rand(&mut raw_key)?;
+        if let Ok(secret_key) = SecretKey::from_slice(&raw_key[..]) {
+            break secret_key;
+        }
+    };
+    keyring.insert(key_from, secret_key);
+    let sealed = seal_keyring(&keyring)?;
+    Ok((keyring, sealed))
+}
+
+pub(crate) fn set_keyring(keyring: Keyring) {
+    *KEYRING
+        .write()
+        .expect("poisoned lock: failed to get obfuscation keys") = keyring;
+}
+
+/// The current key epoch and its (compressed) public key
+pub(crate) fn current_params() -> Option<(BlockHeight, [u8; 33])> {
+    let keyring = KEYRING
+        .read()
+        .expect("poisoned lock: failed to get obfuscation keys");
+    keyring.iter().next_back().map(|(key_from, secret_key)| {
+        (
+            *key_from,
+            PublicKey::from_secret_key(&SECP, secret_key).serialize(),
+        )
+    })
+}
+
+#[inline]
+fn derive_key(
+    key_from: BlockHeight,
+    ephemeral_key: &PublicKey,
+) -> Option<sgx_aes_gcm_128bit_key_t> {
+    let keyring = KEYRING
+        .read()
+        .expect("poisoned lock: failed to get obfuscation keys");
+    let secret_key = keyring.get(&key_from)?;
+    let shared_secret = SharedSecret::new(ephemeral_key, secret_key);
+    let mut key: sgx_aes_gcm_128bit_key_t = [0u8; 16];
+    key.copy_from_slice(&shared_secret[..16]);
+    Some(key)
+}
+
+/// Decrypts and decodes the transaction payload.
+/// Fails if the key is unknown, the authentication tag doesn't match
+/// or the plaintext can't be decoded.
+pub(crate) fn decrypt(payload: &TxObfuscated, txid: &TxId) -> Result<PlainTxAux, EnclaveRejection> {
+    let payload_len = payload.txpayload.len();
+    if payload_len <= EPHEMERAL_KEY_LEN + SGX_AESGCM_MAC_SIZE {
+        return Err(EnclaveRejection::PayloadDecryption);
+    }
+    let (ephemeral_key, encrypted) = payload.txpayload.split_at(EPHEMERAL_KEY_LEN);
+    let ephemeral_key =
+        PublicKey::from_slice(ephemeral_key).map_err(|_| EnclaveRejection::PayloadDecryption)?;
+    let key =
+        derive_key(payload.key_from, &ephemeral_key).ok_or(EnclaveRejection::PayloadDecryption)?;
+    let (ciphertext, tag) = encrypted.split_at(encrypted.len() - SGX_AESGCM_MAC_SIZE);
+    let mut mac: sgx_aes_gcm_128bit_tag_t = [0u8; SGX_AESGCM_MAC_SIZE];
+    mac.copy_from_slice(tag);
+    let mut plaintext: Vec<u8> = vec![0u8; ciphertext.len()];
+    if rsgx_rijndael128GCM_decrypt(
+        &key,
+        ciphertext,
+        &payload.nonce,
+        &txid[..],
+        &mac,
+        &mut plaintext,
+    )
+    .is_err()
+    {
+        return Err(EnclaveRejection::PayloadDecryption);
+    }
+    PlainTxAux::decode(&mut plaintext.as_slice()).map_err(|_| EnclaveRejection::PayloadDecode)
+}
@@ -0,0 +1,80 @@
+use enclave_protocol_ext::SealingPolicy;
+use lazy_static::lazy_static;
+use sgx_tse::rsgx_self_report;
+use sgx_tseal::SgxSealedData;
+use sgx_types::{sgx_attributes_t, sgx_sealed_data_t, sgx_status_t};
+use std::prelude::v1::Vec;
+use std::sync::SgxRwLock;
+
+lazy_static! {
+    static ref POLICY: SgxRwLock<SealingPolicy> = SgxRwLock::new(SealingPolicy::default());
+}
+
+/// Sets the key request used for sealing -- returns the enclave's ISVSVN (which the sealing key is bound to)
+pub(crate) fn set_policy(policy: SealingPolicy) -> Result<u16, sgx_status_t> {
+    if !policy.is_valid() {
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    *POLICY
+        .write()
+        .expect("poisoned lock: failed to get sealing policy") = policy;
+    Ok(rsgx_self_report().body.isv_svn)
+}
+
+/// Seals the payload (authenticating the additional data) into a raw `sgx_sealed_data_t` blob
+pub(crate) fn seal(additional: &[u8], to_seal: &[u8]) -> Result<Vec<u8>, sgx_status_t> {
+    let policy = *POLICY
+        .read()
+        .expect("poisoned lock: failed to get sealing policy");
+    let attribute_mask = sgx_attributes_t {
+        flags: policy.attribute_flags_mask,
+        xfrm: policy.attribute_xfrm_mask,
+    };
+    let sealed_data = SgxSealedData::<[u8]>::seal_data_ex(
+        policy.key_policy,
+        attribute_mask,
+        policy.misc_mask,
+        additional,
+        to_seal,
+    )?;
+    let sealed_log_size = SgxSealedData::<[u8]>::calc_raw_sealed_data_size(
+        sealed_data.get_add_mac_txt_len(),
+        sealed_data.get_encrypt_txt_len(),
+    ) as usize;
+    let mut sealed_log: Vec<u8> = vec![0u8; sealed_log_size];
+
+    unsafe {
+        let sealed_r = sealed_data.to_raw_sealed_data_t(
+            sealed_log.as_mut_ptr() as *mut sgx_sealed_data_t,
+            sealed_log_size as u32,
+        );
+        if sealed_r.is_none() {
+            return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+        }
+    }
+    Ok(sealed_log)
+}
+
+/// Unseals a raw `sgx_sealed_data_t` blob -- returns the additional data and the decrypted payload
+pub(crate) fn unseal(sealed_log: &mut [u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    if sealed_log.len() >= (std::u32::MAX as usize) {
+        return None;
+    }
+    let sealed_data = unsafe {
+        SgxSealedData::<[u8]>::from_raw_sealed_data_t(
+            sealed_log.as_mut_ptr() as *mut sgx_sealed_data_t,
+            sealed_log.len() as u32,
+        )
+    }?;
+    let unsealed_data = sealed_data.unseal_data().ok()?;
+    Some((
+        unsealed_data.get_additional_txt().to_vec(),
+        unsealed_data.get_decrypt_txt().to_vec(),
+    ))
+}
+
+/// Unseals the blob (with the key request it was sealed with) and seals it again with the current policy
+pub(crate) fn reseal(sealed_log: &mut [u8]) -> Result<Vec<u8>, sgx_status_t> {
+    let (additional, plaintext) = unseal(sealed_log).ok_or(sgx_status_t::SGX_ERROR_MAC_MISMATCH)?;
+    seal(&additional, &plaintext)
+}
@@ -0,0 +1,93 @@
+//! # Validation result signing
+//! The enclave generates a secp256k1 signing key on the first start (it only leaves the enclave sealed,
+//! so that the same key is restored after a restart) and signs the `ValidationStatement` of every accepted transaction.
+//! The public key is put in the report data of the enclave's report, so once the report is quoted,
+//! anyone can check that a given enclave build approved a transaction without trusting the host.
+
+use crate::sealing::{seal, unseal};
+use enclave_protocol_ext::{ValidationSignature, ValidationStatement};
+use lazy_static::lazy_static;
+use secp256k1::{
+    key::{PublicKey, SecretKey},
+    All, Message, Secp256k1,
+};
+use sgx_trts::trts::rsgx_read_rand;
+use sgx_tse::rsgx_create_report;
+use sgx_types::{sgx_report_data_t, sgx_report_t, sgx_status_t, sgx_target_info_t};
+use std::prelude::v1::Vec;
+use std::sync::SgxRwLock;
+
+/// the additional (authenticated) data of the sealed signing key
+const SIGNING_KEY_SEALING_TAG: &[u8] = b"validation-signing-key";
+
+lazy_static! {
+    static ref SECP: Secp256k1<All> = Secp256k1::new();
+    static ref SIGNING_KEY: SgxRwLock<Option<SecretKey>> = SgxRwLock::new(None);
+}
+
+/// The signing key (None before it's generated or restored)
+#[inline]
+fn signing_key() -> Result<SecretKey, sgx_status_t> {
+    let key = *SIGNING_KEY
+        .read()
+        .expect("poisoned lock: failed to get signing key");
+    key.ok_or(sgx_status_t::SGX_ERROR_INVALID_STATE)
+}
+
+/// Generates a new signing key -- returns it and its sealed form.
+/// The enclave's key isn't replaced until `set_key` is called (i.e. the sealed form was handed over for storage).
+pub(crate) fn generate_key() -> Result<(SecretKey, Vec<u8>), sgx_status_t> {
+    let mut raw_key = [0u8; 32];
+    let secret_key = loop {
+        rsgx_read_rand(&mut raw_key)?;
+        if let Ok(secret_key) = SecretKey::from_slice(&raw_key[..]) {
+            break secret_key;
+        }
+    };
+    let sealed = seal(SIGNING_KEY_SEALING_TAG, &raw_key)?;
+    Ok((secret_key, sealed))
+}
+
+pub(crate) fn set_key(secret_key: SecretKey) {
+    *SIGNING_KEY
+        .write()
+        .expect("poisoned lock: failed to get signing key") = Some(secret_key);
+}
+
+/// Restores the signing key from the sealed blob (e.g. after the enclave restarted)
+pub(crate) fn restore_key(sealed_key: &mut [u8]) -> Result<(), sgx_status_t> {
+    let (tag, raw_key) = unseal(sealed_key).ok_or(sgx_status_t::SGX_ERROR_INVALID_PARAMETER)?;
+    if tag.as_slice() != SIGNING_KEY_SEALING_TAG {
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let secret_key =
+        SecretKey::from_slice(&raw_key).map_err(|_| sgx_status_t::SGX_ERROR_INVALID_PARAMETER)?;
+    set_key(secret_key);
+    Ok(())
+}
+
+pub(crate) fn sign(statement: &ValidationStatement) -> Result<ValidationSignature, sgx_status_t> {
+    let key = signing_key()?;
+    let message = Message::from_slice(&statement.hash()[..])
+        .map_err(|_| sgx_status_t::SGX_ERROR_UNEXPECTED)?;
+    let compact = SECP.sign(&message, &key).serialize_compact();
+    let mut signature = ValidationSignature {
+        r: [0u8; 32],
+        s: [0u8; 32],
+    };
+    signature.r.copy_from_slice(&compact[..32]);
+    signature.s.copy_from_slice(&compact[32..]);
+    Ok(signature)
+}
+
+/// The compressed public key and the enclave's report (for `target_info`)
+/// whose report data starts with it
+pub(crate) fn report(
+    target_info: &sgx_target_info_t,
+) -> Result<([u8; 33], sgx_report_t), sgx_status_t> {
+    let public_key = PublicKey::from_secret_key(&SECP, &signing_key()?).serialize();
+    let mut report_data = sgx_report_data_t::default();
+    report_data.d[..public_key.len()].copy_from_slice(&public_key);
+    let report = rsgx_create_report(target_info, &report_data)?;
+    Ok((public_key, report))
+}
@@ -0,0 +1,295 @@
+//! # Spent outputs
+//! The enclave keeps the set of the spent transaction outputs, so that it refuses double spends
+//! even if the host passes already spent (sealed) inputs, and the payload nonces used by the delivered
+//! transactions, so that a nonce can't be reused for a different transaction.
+//! The outputs spent (and the nonces used) in a committed block are sealed along with the block height,
+//! and the host stores them; they're chained into a digest
+//! (`digest_n = SHA-256(digest_{n-1} || height || spent outputs || used nonces)`, starting from zeros)
+//! which is kept in the sealed checkpoint, so the host can't leave out or roll back any of them
+//! when it passes them back after a restart.
+
+use crate::checkpoint;
+use crate::sealing::{seal, unseal};
+use chain_core::common::H256;
+use chain_core::state::tendermint::BlockHeight;
+use chain_core::tx::data::input::{TxoIndex, TxoPointer};
+use chain_core::tx::data::TxId;
+use enclave_protocol_ext::EnclaveRejection;
+use lazy_static::lazy_static;
+use parity_scale_codec::{Decode, Encode};
+use sgx_tcrypto::rsgx_sha256_slice;
+use sgx_types::sgx_status_t;
+use std::collections::{BTreeMap, BTreeSet};
+use std::prelude::v1::Vec;
+use std::sync::SgxRwLock;
+
+/// the additional (authenticated) data of the sealed spent outputs
+const SPENT_SEALING_TAG: &[u8] = b"spent-outputs";
+
+/// the digest of the empty set
+pub(crate) const EMPTY_DIGEST: H256 = [0u8; 32];
+
+type OutputKey = (TxId, TxoIndex);
+
+/// a payload nonce (along with the key epoch it was used in) and the transaction encrypted with it
+pub(crate) type UsedNonce = (BlockHeight, [u8; 12], TxId);
+
+type NonceKey = (BlockHeight, [u8; 12]);
+
+#[inline]
+fn key(pointer: &TxoPointer) -> OutputKey {
+    (pointer.id, pointer.index)
+}
+
+struct SpentSet {
+    /// false until the restored set was checked against the checkpoint
+    verified: bool,
+    /// the outputs spent in the committed blocks
+    committed: BTreeSet<OutputKey>,
+    /// the nonces used in the committed blocks
+    committed_nonces: BTreeMap<NonceKey, TxId>,
+    /// the digest of the committed blocks' spent outputs and used nonces
+    digest: H256,
+    /// the outputs spent in the current block
+    pending: BTreeSet<OutputKey>,
+    /// the nonces used in the current block
+    pending_nonces: BTreeMap<NonceKey, TxId>,
+}
+
+/// the blocks' spent outputs passed back by the host (until they're checked)
+struct Restoring {
+    spent: BTreeSet<OutputKey>,
+    nonces: BTreeMap<NonceKey, TxId>,
+    digest: H256,
+    height: BlockHeight,
+}
+
+lazy_static! {
+    static ref SPENT: SgxRwLock<SpentSet> = SgxRwLock::new(SpentSet {
+        verified: false,
+        committed: BTreeSet::new(),
+        committed_nonces: BTreeMap::new(),
+        digest: EMPTY_DIGEST,
+        pending: BTreeSet::new(),
+        pending_nonces: BTreeMap::new(),
+    });
+    static ref RESTORING: SgxRwLock<Option<Restoring>> = SgxRwLock::new(None);
+}
+
+#[inline]
+fn chain_digest(
+    previous: &H256,
+    height: BlockHeight,
+    spent: &[TxoPointer],
+    nonces: &[UsedNonce],
+) -> Result<H256, sgx_status_t> {
+    let mut to_hash = previous.to_vec();
+    to_hash.extend(height.encode());
+    to_hash.extend(spent.encode());
+    to_hash.extend(nonces.encode());
+    rsgx_sha256_slice(&to_hash)
+}
+
+/// Checks that none of the inputs were spent (in a committed block, in the current block,
+/// or earlier in the same call, i.e. in `spent_in_call`)
+pub(crate) fn check(
+    inputs: &[TxoPointer],
+    spent_in_call: &[TxoPointer],
+) -> Result<(), EnclaveRejection> {
+    let spent = SPENT
+        .read()
+        .expect("poisoned lock: failed to get spent outputs");
+    if !spent.verified {
+        return Err(EnclaveRejection::NotInitialized);
+    }
+    let already_spent = inputs.iter().any(|input| {
+        let input_key = key(input);
+        spent.committed.contains(&input_key)
+            || spent.pending.contains(&input_key)
+            || spent_in_call.iter().any(|x| key(x) == input_key)
+    });
+    if already_spent {
+        Err(EnclaveRejection::InputSpent)
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that the payload nonce wasn't used for a different transaction (in a committed block,
+/// in the current block, or earlier in the same call, i.e. in `used_in_call`) -- the same transaction
+/// may be validated more than once (e.g. in the mempool check and in the block delivery)
+pub(crate) fn check_nonce(
+    used: &UsedNonce,
+    used_in_call: &[UsedNonce],
+) -> Result<(), EnclaveRejection> {
+    let spent = SPENT
+        .read()
+        .expect("poisoned lock: failed to get spent outputs");
+    if !spent.verified {
+        return Err(EnclaveRejection::NotInitialized);
+    }
+    let (key_from, nonce, txid) = used;
+    let nonce_key = (*key_from, *nonce);
+    let reused = spent
+        .committed_nonces
+        .get(&nonce_key)
+        .into_iter()
+        .chain(spent.pending_nonces.get(&nonce_key))
+        .chain(
+            used_in_call
+                .iter()
+                .filter(|(k, n, _)| (*k, *n) == nonce_key)
+                .map(|(_, _, id)| id),
+        )
+        .any(|previous| previous != txid);
+    if reused {
+        Err(EnclaveRejection::NonceReuse)
+    } else {
+        Ok(())
+    }
+}
+
+/// Records the outputs spent (and the nonces used) by the delivered transactions in the current block
+pub(crate) fn add_pending(spent_outputs: &[TxoPointer], used_nonces: &[UsedNonce]) {
+    let mut spent = SPENT
+        .write()
+        .expect("poisoned lock: failed to get spent outputs");
+    for pointer in spent_outputs.iter() {
+        spent.pending.insert(key(pointer));
+    }
+    for (key_from, nonce, txid) in used_nonces.iter() {
+        spent.pending_nonces.insert((*key_from, *nonce), *txid);
+    }
+}
+
+/// Seals the outputs spent (and the nonces used) in the current block (committed at `height`) --
+/// returns the next digest and the sealed outputs (None if nothing was spent or used in the block).
+/// The set isn't updated until `commit` is called.
+pub(crate) fn next(height: BlockHeight) -> Result<(H256, Option<Vec<u8>>), sgx_status_t> {
+    let spent = SPENT
+        .read()
+        .expect("poisoned lock: failed to get spent outputs");
+    if !spent.verified {
+        return Err(sgx_status_t::SGX_ERROR_INVALID_STATE);
+    }
+    if spent.pending.is_empty() && spent.pending_nonces.is_empty() {
+        return Ok((spent.digest, None));
+    }
+    let pending: Vec<TxoPointer> = spent
+        .pending
+        .iter()
+        .map(|(id, index)| TxoPointer::new(*id, *index as usize))
+        .collect();
+    let pending_nonces: Vec<UsedNonce> = spent
+        .pending_nonces
+        .iter()
+        .map(|((key_from, nonce), txid)| (*key_from, *nonce, *txid))
+        .collect();
+    let digest = chain_digest(&spent.digest, height, &pending, &pending_nonces)?;
+    let to_seal: (BlockHeight, Vec<TxoPointer>, Vec<UsedNonce>) = (height, pending, pending_nonces);
+    let sealed = seal(SPENT_SEALING_TAG, &to_seal.encode())?;
+    Ok((digest, Some(sealed)))
+}
+
+/// Moves the current block's spent outputs and used nonces to the committed ones
+pub(crate) fn commit(digest: H256) {
+    let mut spent = SPENT
+        .write()
+        .expect("poisoned lock: failed to get spent outputs");
+    let pending = std::mem::replace(&mut spent.pending, BTreeSet::new());
+    spent.committed.extend(pending);
+    let pending_nonces = std::mem::replace(&mut spent.pending_nonces, BTreeMap::new());
+    spent.committed_nonces.extend(pending_nonces);
+    spent.digest = digest;
+}
+
+/// Forgets the outputs spent and the nonces used in the current block (when the block is abandoned)
+pub(crate) fn abandon() {
+    let mut spent = SPENT
+        .write()
+        .expect("poisoned lock: failed to get spent outputs");
+    spent.pending.clear();
+    spent.pending_nonces.clear();
+}
+
+/// Adds the sealed spent outputs and used nonces of the committed blocks (passed in the height order);
+/// the ones of a block that wasn't committed are ignored
+/// (if they can't be restored, the restoration needs to start over)
+pub(crate) fn restore(sealed_blocks: Vec<Vec<u8>>) -> Result<(), sgx_status_t> {
+    let mut restoring = RESTORING
+        .write()
+        .expect("poisoned lock: failed to get spent outputs");
+    let result = restore_blocks(
+        restoring.get_or_insert_with(|| Restoring {
+            spent: BTreeSet::new(),
+            nonces: BTreeMap::new(),
+            digest: EMPTY_DIGEST,
+            height: 0,
+        }),
+        sealed_blocks,
+    );
+    if result.is_err() {
+        *restoring = None;
+    }
+    result
+}
+
+#[inline]
+fn restore_blocks(state: &mut Restoring, sealed_blocks: Vec<Vec<u8>>) -> Result<(), sgx_status_t> {
+    let committed_height =
+        checkpoint::next_height().ok_or(sgx_status_t::SGX_ERROR_INVALID_STATE)? - 1;
+    for mut sealed in sealed_blocks.into_iter() {
+        let (tag, raw) = unseal(&mut sealed).ok_or(sgx_status_t::SGX_ERROR_MAC_MISMATCH)?;
+        if tag.as_slice() != SPENT_SEALING_TAG {
+            return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+        }
+        let (height, pointers, nonces): (BlockHeight, Vec<TxoPointer>, Vec<UsedNonce>) =
+            Decode::decode(&mut raw.as_slice())
+                .map_err(|_| sgx_status_t::SGX_ERROR_MAC_MISMATCH)?;
+        if height > committed_height {
+            continue;
+        }
+        if height <= state.height {
+            return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+        }
+        state.digest = chain_digest(&state.digest, height, &pointers, &nonces)?;
+        state.height = height;
+        state.spent.extend(pointers.iter().map(key));
+        state.nonces.extend(
+            nonces
+                .into_iter()
+                .map(|(key_from, nonce, txid)| ((key_from, nonce), txid)),
+        );
+    }
+    Ok(())
+}
+
+/// Checks the restored spent outputs and used nonces against the checkpoint's digest and starts using them
+pub(crate) fn finish_restore() -> Result<(), sgx_status_t> {
+    let expected = checkpoint::spent_digest().ok_or(sgx_status_t::SGX_ERROR_INVALID_STATE)?;
+    let restored = RESTORING
+        .write()
+        .expect("poisoned lock: failed to get spent outputs")
+        .take()
+        .unwrap_or(Restoring {
+            spent: BTreeSet::new(),
+            nonces: BTreeMap::new(),
+            digest: EMPTY_DIGEST,
+            height: 0,
+        });
+    if restored.digest != expected {
+        return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+    }
+    let mut spent = SPENT
+        .write()
+        .expect("poisoned lock: failed to get spent outputs");
+    *spent = SpentSet {
+        verified: true,
+        committed: restored.spent,
+        committed_nonces: restored.nonces,
+        digest: restored.digest,
+        pending: BTreeSet::new(),
+        pending_nonces: BTreeMap::new(),
+    };
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! # Staked state transitions
+//! The staked state resulting from a deposit or a withdrawal is computed in the enclave
+//! (with checked arithmetic, i.e. an overflow or underflow is a validation error).
+
+use chain_core::init::coin::{sum_coins, Coin};
+use chain_core::state::account::{DepositBondTx, StakedState, WithdrawUnbondedTx};
+use chain_core::tx::fee::Fee;
+use chain_core::ChainInfo;
+use chain_tx_validation::Error;
+
+/// The deposited amount (the inputs minus the fee) is bonded to the account
+/// (which is created if it didn't exist)
+pub(crate) fn deposit(
+    account: Option<StakedState>,
+    tx: &DepositBondTx,
+    input_coins: Coin,
+    info: &ChainInfo,
+) -> Result<StakedState, Error> {
+    let amount = (input_coins - info.min_fee_computed.to_coin()).map_err(Error::InvalidSum)?;
+    match account {
+        Some(mut account) => {
+            // `StakedState::deposit` panics on an overflow
+            (account.bonded + amount).map_err(Error::InvalidSum)?;
+            account.deposit(amount);
+            Ok(account)
+        }
+        None => Ok(StakedState::new_init(
+            amount,
+            info.previous_block_time,
+            tx.to_staked_account,
+            true,
+        )),
+    }
+}
+
+/// All the unbonded amount is withdrawn (it needs to cover the outputs and the fee)
+pub(crate) fn withdraw(
+    mut account: StakedState,
+    tx: &WithdrawUnbondedTx,
+    fee: Fee,
+) -> Result<StakedState, Error> {
+    let outputs =
+        sum_coins(tx.outputs.iter().map(|output| output.value)).map_err(Error::InvalidSum)?;
+    let withdrawn = (outputs + fee.to_coin()).map_err(Error::InvalidSum)?;
+    (account.unbonded - withdrawn).map_err(Error::InvalidSum)?;
+    // `StakedState::withdraw` panics on a nonce overflow
+    account
+        .nonce
+        .checked_add(1)
+        .ok_or(Error::AccountIncorrectNonce)?;
+    account.withdraw();
+    Ok(account)
+}
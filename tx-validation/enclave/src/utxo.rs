@@ -0,0 +1,113 @@
+//! # Unspent outputs commitment
+//! The enclave keeps an incremental commitment to the unspent outputs it validated:
+//! the sum (modulo 2^256) of `SHA-256(txid || index)` of each unspent output
+//! (the transaction id commits to the output's contents).
+//! The committed sum is kept in the sealed checkpoint; the changes of the current block are added
+//! to it when the block is committed. It's meant for comparing the enclave state across validators
+//! (an additive hash isn't collision resistant against an adversary choosing the outputs).
+
+use crate::checkpoint;
+use chain_core::common::H256;
+use chain_core::tx::data::input::{TxoIndex, TxoPointer};
+use chain_core::tx::data::TxId;
+use lazy_static::lazy_static;
+use parity_scale_codec::Encode;
+use sgx_tcrypto::rsgx_sha256_slice;
+use sgx_types::sgx_status_t;
+use std::sync::SgxRwLock;
+
+/// the commitment to the empty set
+pub(crate) const EMPTY_ROOT: H256 = [0u8; 32];
+
+/// The changes of some delivered transactions
+#[derive(Default)]
+pub(crate) struct Delta {
+    /// the sum of the created outputs
+    created: H256,
+    /// the sum of the spent outputs
+    spent: H256,
+}
+
+lazy_static! {
+    /// the changes of the current block
+    static ref PENDING: SgxRwLock<Delta> = SgxRwLock::new(Delta::default());
+}
+
+#[inline]
+fn add(sum: &mut H256, x: &H256) {
+    let mut carry = 0u16;
+    for i in (0..32).rev() {
+        let s = u16::from(sum[i]) + u16::from(x[i]) + carry;
+        sum[i] = s as u8;
+        carry = s >> 8;
+    }
+}
+
+#[inline]
+fn sub(sum: &mut H256, x: &H256) {
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let d = i16::from(sum[i]) - i16::from(x[i]) - borrow;
+        if d < 0 {
+            sum[i] = (d + 256) as u8;
+            borrow = 1;
+        } else {
+            sum[i] = d as u8;
+            borrow = 0;
+        }
+    }
+}
+
+#[inline]
+fn element(txid: &TxId, index: TxoIndex) -> Result<H256, sgx_status_t> {
+    let mut to_hash = txid.to_vec();
+    to_hash.extend(index.encode());
+    rsgx_sha256_slice(&to_hash)
+}
+
+impl Delta {
+    /// Computes the changes of transactions: `created` are their ids and numbers of outputs,
+    /// `spent` are the outputs they spent
+    pub(crate) fn new(
+        created: &[(TxId, usize)],
+        spent: &[TxoPointer],
+    ) -> Result<Self, sgx_status_t> {
+        let mut delta = Delta::default();
+        for (txid, no_of_outputs) in created.iter() {
+            for index in 0..*no_of_outputs {
+                add(&mut delta.created, &element(txid, index as TxoIndex)?);
+            }
+        }
+        for pointer in spent.iter() {
+            add(&mut delta.spent, &element(&pointer.id, pointer.index)?);
+        }
+        Ok(delta)
+    }
+}
+
+/// Adds the changes to the current block
+pub(crate) fn add_pending(delta: Delta) {
+    let mut pending = PENDING
+        .write()
+        .expect("poisoned lock: failed to get utxo commitment");
+    add(&mut pending.created, &delta.created);
+    add(&mut pending.spent, &delta.spent);
+}
+
+/// The commitment including the current block's changes (None if the checkpoint wasn't checked yet)
+pub(crate) fn root() -> Option<H256> {
+    let mut root = checkpoint::utxo_root()?;
+    let pending = PENDING
+        .read()
+        .expect("poisoned lock: failed to get utxo commitment");
+    add(&mut root, &pending.created);
+    sub(&mut root, &pending.spent);
+    Some(root)
+}
+
+/// Clears the current block's changes (once they're in the committed checkpoint, or when the block is abandoned)
+pub(crate) fn commit() {
+    *PENDING
+        .write()
+        .expect("poisoned lock: failed to get utxo commitment") = Delta::default();
+}
@@ -4,7 +4,6 @@ set -e
 source /root/.docker_bashrc
 
 echo "[Config] SGX_MODE=${SGX_MODE}"
-echo "[Config] NETWORK_ID=${NETWORK_ID}"
 
 if [ x"${SGX_MODE}" == "xHW" ]; then
   LD_LIBRARY_PATH=/opt/intel/libsgx-enclave-common/aesm /opt/intel/libsgx-enclave-common/aesm/aesm_service &